@@ -0,0 +1,11 @@
+//! Hash maps used by the tree, from `std` or from `hashbrown` in `no_std` builds.
+
+#[cfg(feature = "std")]
+pub use std::collections::{HashMap, HashSet};
+#[cfg(feature = "std")]
+pub use std::collections::hash_map::RandomState;
+
+#[cfg(not(feature = "std"))]
+pub use hashbrown::{HashMap, HashSet};
+#[cfg(not(feature = "std"))]
+pub use hashbrown::hash_map::DefaultHashBuilder as RandomState;
@@ -0,0 +1,47 @@
+#[cfg(feature = "std")]
+use std::error;
+use std::fmt;
+use std::result;
+
+/// Reasons for rejecting tree configuration or item volumes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// Branch size must allow at least one item per node.
+    ZeroBranchSize,
+    /// World volume must be greater than zero.
+    EmptyWorld,
+    /// Volume has a NaN or infinite coordinate.
+    NonFiniteBounds,
+    /// Volume has `min` greater than `max` on at least one axis.
+    InvertedBounds,
+    /// Item key is already used in the tree.
+    DuplicateKey,
+    /// Voxel size must be finite and greater than zero.
+    InvalidVoxelSize,
+    /// Coordinate does not fit into its type, such as the extent of an integer world, an
+    /// integer coordinate shifted past its bounds, or a voxel index out of the range of `i64`.
+    CoordinateOutOfRange,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::ZeroBranchSize => write!(f, "branch size must be greater than zero"),
+            Error::EmptyWorld => write!(f, "world volume must be greater than zero"),
+            Error::NonFiniteBounds => write!(f, "bounding box has NaN or infinite coordinates"),
+            Error::InvertedBounds => {
+                write!(f, "bounding box has min greater than max on at least one axis")
+            }
+            Error::DuplicateKey => write!(f, "item key is already used in the tree"),
+            Error::InvalidVoxelSize => {
+                write!(f, "voxel size must be finite and greater than zero")
+            }
+            Error::CoordinateOutOfRange => write!(f, "coordinate is out of range"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for Error {}
+
+pub type Result<T> = result::Result<T, Error>;
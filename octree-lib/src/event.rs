@@ -0,0 +1,20 @@
+/// Node identifier that stays the same for the whole lifetime of the node.
+///
+/// Identifiers are never reused within a tree.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub u64);
+
+/// Structural change of the tree, see `Octree::record_events`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event<K> {
+    /// Node was subdivided into 8 new `children`.
+    Subdivided { node: NodeId, children: [NodeId; 8] },
+    /// All `children` of the node were removed and the node became a leaf.
+    Collapsed { node: NodeId, children: [NodeId; 8] },
+    /// New item was added to the node.
+    ItemInserted { id: K, node: NodeId },
+    /// Item was moved to a different node.
+    ItemMoved { id: K, from: NodeId, to: NodeId },
+    /// Item was removed from the tree.
+    ItemRemoved { id: K, node: NodeId },
+}
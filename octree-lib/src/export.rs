@@ -0,0 +1,254 @@
+//! Export of tree structure to common file formats for offline inspection.
+//!
+//! Node and item volumes are written as boxes to Wavefront OBJ or PLY files, coloured by the
+//! `goodness` reported by `DebugIter`: nodes fade from red at the top of the tree to green
+//! deep down, and items use the same scheme with full blue component. The node hierarchy can
+//! be written as a Graphviz DOT graph.
+//!
+//! Depths are counted like `NodeRef::depth`, from `0` at the root node, and items have the
+//! depth of the node that stores them.
+
+use std::f64;
+use std::fmt;
+use std::hash::{Hash, BuildHasher};
+use std::io::{self, Write};
+use cgmath::*;
+use collision::*;
+use {Octree, DebugItem, NodeRef, Scalar};
+use iterator::debug::calculate_goodness;
+
+/// Box corner indices of the 6 faces, wound counter-clockwise when viewed from outside.
+const BOX_FACES: [[usize; 4]; 6] = [[0, 2, 3, 1],
+                                    [4, 5, 7, 6],
+                                    [0, 1, 5, 4],
+                                    [2, 6, 7, 3],
+                                    [0, 4, 6, 2],
+                                    [1, 3, 7, 5]];
+
+struct ExportBox {
+    corners: [[f64; 3]; 8],
+    color: [u8; 3],
+    depth: usize,
+    goodness: f32,
+    name: String,
+}
+
+fn collect_boxes<S, K, H>(tree: &Octree<S, K, H>) -> Vec<ExportBox>
+    where S: Scalar,
+          K: Clone + Eq + Hash + fmt::Debug,
+          H: BuildHasher
+{
+    // `DebugIter` does not report the root node, and counts depth from `1` at the root
+    let root = tree.root_node();
+    let goodness = calculate_goodness(1);
+    let mut boxes = vec![ExportBox {
+                             corners: corners(&root.bounds()),
+                             color: color(goodness, false),
+                             depth: root.depth(),
+                             goodness: goodness,
+                             name: "node_0".into(),
+                         }];
+
+    let mut node_count = 1;
+    for debug_item in tree.debug_items() {
+        match debug_item {
+            DebugItem::Node { bb, depth, goodness, .. } => {
+                boxes.push(ExportBox {
+                    corners: corners(&bb),
+                    color: color(goodness, false),
+                    depth: depth - 1,
+                    goodness: goodness,
+                    name: format!("node_{}", node_count),
+                });
+                node_count += 1;
+            }
+            DebugItem::Item { item, depth, goodness, .. } => {
+                boxes.push(ExportBox {
+                    corners: corners(&item.bb),
+                    color: color(goodness, true),
+                    depth: depth - 1,
+                    goodness: goodness,
+                    name: format!("item_{:?}", item.id),
+                });
+            }
+        }
+    }
+
+    boxes
+}
+
+/// Write node and item volumes as a Wavefront OBJ mesh.
+///
+/// Every volume is written as a separate object with 6 quad faces. Vertex colours are
+/// written after the vertex position, which is understood by Blender and MeshLab.
+pub fn write_obj<S, K, H, W>(tree: &Octree<S, K, H>, out: &mut W) -> io::Result<()>
+    where S: Scalar,
+          K: Clone + Eq + Hash + fmt::Debug,
+          H: BuildHasher,
+          W: Write
+{
+    writeln!(out, "# octree export")?;
+
+    let mut vertex_offset = 1;
+    for b in collect_boxes(tree) {
+        writeln!(out, "o {}", obj_name(&b.name))?;
+        writeln!(out, "# depth {} goodness {}", b.depth, b.goodness)?;
+        for c in &b.corners {
+            writeln!(out,
+                     "v {} {} {} {} {} {}",
+                     c[0],
+                     c[1],
+                     c[2],
+                     b.color[0] as f32 / 255.0,
+                     b.color[1] as f32 / 255.0,
+                     b.color[2] as f32 / 255.0)?;
+        }
+        for face in &BOX_FACES {
+            writeln!(out,
+                     "f {} {} {} {}",
+                     vertex_offset + face[0],
+                     vertex_offset + face[1],
+                     vertex_offset + face[2],
+                     vertex_offset + face[3])?;
+        }
+        vertex_offset += 8;
+    }
+
+    Ok(())
+}
+
+/// Write node and item volumes as an ASCII PLY mesh.
+///
+/// Besides the position and colour, every vertex has `depth` and `goodness` properties of
+/// the volume it belongs to.
+pub fn write_ply<S, K, H, W>(tree: &Octree<S, K, H>, out: &mut W) -> io::Result<()>
+    where S: Scalar,
+          K: Clone + Eq + Hash + fmt::Debug,
+          H: BuildHasher,
+          W: Write
+{
+    let boxes = collect_boxes(tree);
+
+    writeln!(out, "ply")?;
+    writeln!(out, "format ascii 1.0")?;
+    writeln!(out, "comment octree export")?;
+    writeln!(out, "element vertex {}", boxes.len() * 8)?;
+    writeln!(out, "property double x")?;
+    writeln!(out, "property double y")?;
+    writeln!(out, "property double z")?;
+    writeln!(out, "property uchar red")?;
+    writeln!(out, "property uchar green")?;
+    writeln!(out, "property uchar blue")?;
+    writeln!(out, "property int depth")?;
+    writeln!(out, "property float goodness")?;
+    writeln!(out, "element face {}", boxes.len() * 6)?;
+    writeln!(out, "property list uchar int vertex_indices")?;
+    writeln!(out, "end_header")?;
+
+    for b in &boxes {
+        for c in &b.corners {
+            writeln!(out,
+                     "{} {} {} {} {} {} {} {}",
+                     c[0],
+                     c[1],
+                     c[2],
+                     b.color[0],
+                     b.color[1],
+                     b.color[2],
+                     b.depth,
+                     b.goodness)?;
+        }
+    }
+
+    for i in 0..boxes.len() {
+        for face in &BOX_FACES {
+            writeln!(out,
+                     "4 {} {} {} {}",
+                     i * 8 + face[0],
+                     i * 8 + face[1],
+                     i * 8 + face[2],
+                     i * 8 + face[3])?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Write node hierarchy as a Graphviz DOT graph.
+///
+/// Every node is labeled with its depth and the number of items stored directly in it,
+/// edges are labeled with the child octant index. Empty leaf nodes are drawn dotted.
+pub fn write_dot<S, K, H, W>(tree: &Octree<S, K, H>, out: &mut W) -> io::Result<()>
+    where S: Scalar,
+          K: Clone + Eq + Hash,
+          H: BuildHasher,
+          W: Write
+{
+    writeln!(out, "digraph octree {{")?;
+    writeln!(out, "    node [shape=box];")?;
+
+    let mut next_id = 0;
+    write_dot_node(tree.root_node(), &mut next_id, out)?;
+
+    writeln!(out, "}}")?;
+
+    Ok(())
+}
+
+fn write_dot_node<S, K, W>(node: NodeRef<S, K>,
+                           next_id: &mut usize,
+                           out: &mut W)
+                           -> io::Result<usize>
+    where S: Scalar,
+          K: Clone + Eq + Hash,
+          W: Write
+{
+    let id = *next_id;
+    *next_id += 1;
+
+    let style = if !node.is_branch() && node.items().is_empty() {
+        ", style=dotted"
+    } else {
+        ""
+    };
+    writeln!(out,
+             "    n{} [label=\"depth {}\\n{} items\"{}];",
+             id,
+             node.depth(),
+             node.items().len(),
+             style)?;
+
+    for (octant, child) in node.children().enumerate() {
+        let child_id = write_dot_node(child, next_id, out)?;
+        writeln!(out, "    n{} -> n{} [label=\"{}\"];", id, child_id, octant)?;
+    }
+
+    Ok(id)
+}
+
+fn corners<S>(bb: &Aabb3<S>) -> [[f64; 3]; 8]
+    where S: BaseNum
+{
+    let mut result = [[0.0; 3]; 8];
+    for (i, p) in bb.to_corners().iter().enumerate() {
+        result[i] = [to_f64(p.x), to_f64(p.y), to_f64(p.z)];
+    }
+    result
+}
+
+fn to_f64<S>(value: S) -> f64
+    where S: BaseNum
+{
+    value.to_f64().unwrap_or(f64::NAN)
+}
+
+fn color(goodness: f32, is_item: bool) -> [u8; 3] {
+    [((1.0 - goodness) * 255.0) as u8,
+     (goodness * 255.0) as u8,
+     if is_item { 255 } else { 0 }]
+}
+
+/// OBJ object names can not contain whitespace.
+fn obj_name(name: &str) -> String {
+    name.chars().map(|c| if c.is_whitespace() { '_' } else { c }).collect()
+}
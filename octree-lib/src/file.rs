@@ -0,0 +1,679 @@
+//! Persistent tree stored in a file, for data sets that do not fit in memory.
+//!
+//! `write` stores the nodes of an in-memory `Octree` one after another in depth-first
+//! order, so that a subtree occupies a contiguous run of pages. A node never crosses a page
+//! boundary unless it is larger than a page, in which case it starts a new page.
+//! `OctreeFile` reads the file header only, and loads a node the first time a query reaches
+//! it, so a query near one corner of the world touches only the pages of the nodes on the
+//! way.
+//!
+//! All values are little endian. Coordinates are stored as `f64`, and item ids as `u64`,
+//! see `FileKey`. The file starts with a header page:
+//!
+//! | Bytes  | Value                                                         |
+//! |--------|---------------------------------------------------------------|
+//! | 0..8   | magic `OCTREE\0\x01`                                          |
+//! | 8..12  | page size                                                     |
+//! | 12..16 | flags, `1` if items can be stored in several nodes            |
+//! | 16..24 | number of items                                               |
+//! | 24..32 | number of nodes                                               |
+//! | 32..40 | offset of the root node                                       |
+//!
+//! Every node is stored as its volume, flags (`1` for a branch, `2` if it has content
+//! bounds), number of items and content bounds. Branches follow with their 8 children, each
+//! as the offset, flags, 4 bytes of padding and content bounds, so that a query can skip a
+//! child without loading it. The items come last, each as the id followed by its volume.
+//! Volumes are stored as `min` followed by `max`.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::cmp::Ordering;
+use std::f64;
+use std::fmt;
+use std::mem;
+use std::fs::File;
+use std::hash::{Hash, BuildHasher};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use cgmath::*;
+use collision::*;
+use {Octree, NodeRef, Item, Overlap, Scalar, StraddleMode};
+use query::{ray_entry, is_closer};
+
+const MAGIC: &[u8; 8] = b"OCTREE\0\x01";
+const HEADER_SIZE: usize = 40;
+const BB_SIZE: usize = 48;
+const NODE_HEADER_SIZE: usize = 2 * BB_SIZE + 8;
+const CHILD_SIZE: usize = 16 + BB_SIZE;
+const ITEM_SIZE: usize = 8 + BB_SIZE;
+const MIN_PAGE_SIZE: usize = 1024;
+const BRANCH: u32 = 1;
+const HAS_CONTENT: u32 = 2;
+
+/// Item id that can be stored in a file as a 64 bit number.
+///
+/// Signed ids are stored as their two's complement `i64` bits.
+pub trait FileKey: Sized {
+    fn to_file_key(&self) -> u64;
+
+    /// Convert a stored number back, returns `None` if it is out of the range of the type.
+    fn from_file_key(key: u64) -> Option<Self>;
+}
+
+macro_rules! impl_file_key_unsigned {
+    ($($t:ty),*) => {
+        $(
+            impl FileKey for $t {
+                #[inline]
+                fn to_file_key(&self) -> u64 {
+                    *self as u64
+                }
+
+                #[inline]
+                fn from_file_key(key: u64) -> Option<$t> {
+                    <$t>::try_from(key).ok()
+                }
+            }
+        )*
+    }
+}
+
+macro_rules! impl_file_key_signed {
+    ($($t:ty),*) => {
+        $(
+            impl FileKey for $t {
+                #[inline]
+                fn to_file_key(&self) -> u64 {
+                    *self as i64 as u64
+                }
+
+                #[inline]
+                fn from_file_key(key: u64) -> Option<$t> {
+                    <$t>::try_from(key as i64).ok()
+                }
+            }
+        )*
+    }
+}
+
+impl_file_key_unsigned!(u8, u16, u32, u64, usize);
+impl_file_key_signed!(i8, i16, i32, i64, isize);
+
+/// Write `tree` with pages of 4096 bytes, see `write_with_page_size`.
+pub fn write<S, K, H, W>(tree: &Octree<S, K, H>, out: W) -> io::Result<()>
+    where S: Scalar,
+          K: Clone + Eq + Hash + fmt::Debug + FileKey,
+          H: BuildHasher,
+          W: Write
+{
+    write_with_page_size(tree, 4096, out)
+}
+
+/// Write `tree` in the format read by `OctreeFile`, storing nodes in pages of `page_size`
+/// bytes.
+///
+/// Returns an `InvalidInput` error if `page_size` is less than 1024 bytes, or a coordinate
+/// can not be stored exactly as `f64`, such as an `i64` coordinate beyond `2^53`.
+pub fn write_with_page_size<S, K, H, W>(tree: &Octree<S, K, H>,
+                                        page_size: usize,
+                                        mut out: W)
+                                        -> io::Result<()>
+    where S: Scalar,
+          K: Clone + Eq + Hash + fmt::Debug + FileKey,
+          H: BuildHasher,
+          W: Write
+{
+    if page_size < MIN_PAGE_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "page size is too small"));
+    }
+
+    // offsets of all nodes are needed before the first one is written
+    let mut layout = Vec::new();
+    let mut end = page_size;
+    lay_out(tree.root_node(), page_size, &mut end, &mut layout);
+
+    // nothing is written unless every volume can be stored exactly
+    for entry in &layout {
+        let node = &entry.node;
+        let volumes = node.content_bounds().into_iter().chain(Some(node.bounds()));
+        for bb in volumes.chain(node.items().iter().map(|item| item.bb)) {
+            if !is_exact(&bb) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "coordinate can not be stored exactly"));
+            }
+        }
+    }
+
+    let mut header = Vec::with_capacity(page_size);
+    header.extend_from_slice(MAGIC);
+    put_u32(&mut header, page_size as u32);
+    put_u32(&mut header, if tree.straddle_mode() == StraddleMode::Children { 1 } else { 0 });
+    put_u64(&mut header, tree.into_iter().count() as u64);
+    put_u64(&mut header, layout.len() as u64);
+    put_u64(&mut header, layout[0].offset as u64);
+    header.resize(page_size, 0);
+    out.write_all(&header)?;
+
+    let mut position = page_size;
+    let mut record = Vec::new();
+    for entry in &layout {
+        record.clear();
+        record.resize(entry.offset - position, 0);
+        encode_node(&entry.node, &entry.children, &mut record);
+        out.write_all(&record)?;
+        position = entry.offset + node_size(&entry.node);
+    }
+
+    out.flush()
+}
+
+struct NodeLayout<'a, S: 'a, K: 'a>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    node: NodeRef<'a, S, K>,
+    offset: usize,
+    children: [u64; 8],
+}
+
+/// Place `node` and its subtree after `end`, returning the offset of the node.
+fn lay_out<'a, S, K>(node: NodeRef<'a, S, K>,
+                     page_size: usize,
+                     end: &mut usize,
+                     layout: &mut Vec<NodeLayout<'a, S, K>>)
+                     -> usize
+    where S: Scalar,
+          K: Clone + Eq + Hash
+{
+    let size = node_size(&node);
+    let page_offset = *end % page_size;
+    if page_offset != 0 && (size > page_size || page_offset + size > page_size) {
+        *end += page_size - page_offset;
+    }
+
+    let offset = *end;
+    let index = layout.len();
+    *end += size;
+    layout.push(NodeLayout {
+        node: node,
+        offset: offset,
+        children: [0; 8],
+    });
+
+    for (octant, child) in node.children().enumerate() {
+        let child_offset = lay_out(child, page_size, end, layout);
+        layout[index].children[octant] = child_offset as u64;
+    }
+
+    offset
+}
+
+fn node_size<S, K>(node: &NodeRef<S, K>) -> usize
+    where S: Scalar,
+          K: Clone + Eq + Hash
+{
+    let children = if node.is_branch() { 8 } else { 0 };
+    NODE_HEADER_SIZE + children * CHILD_SIZE + node.items().len() * ITEM_SIZE
+}
+
+fn encode_node<S, K>(node: &NodeRef<S, K>, children: &[u64; 8], out: &mut Vec<u8>)
+    where S: Scalar,
+          K: Clone + Eq + Hash + FileKey
+{
+    let content = node.content_bounds();
+    let branch = if node.is_branch() { BRANCH } else { 0 };
+    put_bb(out, &node.bounds());
+    put_u32(out, branch | content_flag(&content));
+    put_u32(out, node.items().len() as u32);
+    put_content(out, &content);
+
+    for (child, &offset) in node.children().zip(children) {
+        let content = child.content_bounds();
+        put_u64(out, offset);
+        put_u32(out, content_flag(&content));
+        put_u32(out, 0);
+        put_content(out, &content);
+    }
+
+    for item in node.items() {
+        put_u64(out, item.id.to_file_key());
+        put_bb(out, &item.bb);
+    }
+}
+
+/// Returns `true` if the coordinates of `bb` convert to `f64` and back without change.
+fn is_exact<S>(bb: &Aabb3<S>) -> bool
+    where S: BaseNum
+{
+    [bb.min, bb.max].iter().all(|point| {
+        (0..3).all(|axis| {
+            match point[axis].to_f64() {
+                // NaN is stored as NaN, but is not equal to itself
+                Some(value) if value.is_nan() => true,
+                Some(value) => S::from(value) == Some(point[axis]),
+                None => false,
+            }
+        })
+    })
+}
+
+fn content_flag<S>(content: &Option<Aabb3<S>>) -> u32 {
+    if content.is_some() { HAS_CONTENT } else { 0 }
+}
+
+/// Node loaded from a file.
+struct FileNode<S, K>
+    where S: BaseNum
+{
+    bounds: Aabb3<S>,
+    content: Option<Aabb3<S>>,
+    items: Vec<Item<S, K>>,
+    /// Empty for leaf nodes, otherwise the 8 children in octant order.
+    children: Vec<FileChild<S>>,
+}
+
+/// Reference to a child node, with the bounds needed to decide whether to load it.
+#[derive(Clone, Copy)]
+struct FileChild<S>
+    where S: BaseNum
+{
+    offset: u64,
+    content: Option<Aabb3<S>>,
+}
+
+/// Tree stored by `write`, loading nodes on demand.
+///
+/// Queries read the nodes they visit the first time they need them, and keep the most
+/// recently used ones in memory, up to the cache limit. Nodes are skipped by their content
+/// bounds, just like in the in-memory tree.
+///
+/// The file is not trusted: sizes and offsets that do not fit the header, the pages or the
+/// depth-first layout written by `write` are reported as `InvalidData` errors.
+pub struct OctreeFile<R, S, K>
+    where S: BaseNum
+{
+    input: R,
+    len: u64,
+    page_size: usize,
+    unique: bool,
+    item_count: usize,
+    node_count: usize,
+    root: u64,
+    cache_limit: usize,
+    /// Loaded nodes with the time they were last used.
+    nodes: HashMap<u64, (FileNode<S, K>, u64)>,
+    /// Offsets of the loaded nodes by the time they were last used.
+    recent: BTreeMap<u64, u64>,
+    time: u64,
+}
+
+/// Nearest hit of a ray, see `OctreeFile::ray_cast`.
+type RayHit<S, K> = (Point3<S>, Item<S, K>);
+
+impl<S, K> OctreeFile<File, S, K>
+    where S: Scalar + BaseFloat,
+          K: Clone + Eq + Hash + FileKey
+{
+    /// Open the file at `path`.
+    pub fn open<P>(path: P) -> io::Result<OctreeFile<File, S, K>>
+        where P: AsRef<Path>
+    {
+        OctreeFile::new(File::open(path)?)
+    }
+}
+
+impl<R, S, K> OctreeFile<R, S, K>
+    where R: Read + Seek,
+          S: Scalar + BaseFloat,
+          K: Clone + Eq + Hash + FileKey
+{
+    /// Read the header of a tree written to `input`.
+    ///
+    /// At most 4096 nodes are kept loaded, see `set_cache_limit`.
+    ///
+    /// Returns an `InvalidData` error if `input` does not start with a tree header.
+    pub fn new(mut input: R) -> io::Result<OctreeFile<R, S, K>> {
+        let len = input.seek(SeekFrom::End(0))?;
+        let mut header = [0; HEADER_SIZE];
+        input.seek(SeekFrom::Start(0))?;
+        input.read_exact(&mut header)?;
+        if &header[0..8] != MAGIC {
+            return invalid("not an octree file");
+        }
+
+        let page_size = get_u32(&header, 8) as usize;
+        if page_size < MIN_PAGE_SIZE {
+            return invalid("invalid page size");
+        }
+
+        // every node and item takes some room after the header page
+        let room = len.saturating_sub(page_size as u64);
+        let item_count = get_u64(&header, 16);
+        let node_count = get_u64(&header, 24);
+        let root = get_u64(&header, 32);
+        if node_count == 0 || node_count > room / NODE_HEADER_SIZE as u64 {
+            return invalid("invalid node count");
+        }
+        if item_count > room / ITEM_SIZE as u64 {
+            return invalid("invalid item count");
+        }
+        if root < page_size as u64 || root >= len {
+            return invalid("invalid root offset");
+        }
+
+        Ok(OctreeFile {
+            input: input,
+            len: len,
+            page_size: page_size,
+            unique: get_u32(&header, 12) & 1 != 0,
+            item_count: item_count as usize,
+            node_count: node_count as usize,
+            root: root,
+            cache_limit: 4096,
+            nodes: HashMap::new(),
+            recent: BTreeMap::new(),
+            time: 0,
+        })
+    }
+
+    /// Number of items in the tree.
+    pub fn item_count(&self) -> usize {
+        self.item_count
+    }
+
+    /// Number of nodes in the tree, including the root node.
+    pub fn node_count(&self) -> usize {
+        self.node_count
+    }
+
+    /// Number of nodes loaded so far.
+    pub fn loaded_node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Get the maximum number of nodes kept loaded.
+    pub fn cache_limit(&self) -> usize {
+        self.cache_limit
+    }
+
+    /// Keep at most `limit` nodes loaded, forgetting the least recently used ones first.
+    ///
+    /// At least one node is kept, even if `limit` is zero.
+    pub fn set_cache_limit(&mut self, limit: usize) {
+        self.cache_limit = limit;
+        self.evict(limit.max(1));
+    }
+
+    /// Forget all loaded nodes, they will be read again when needed.
+    pub fn clear_cache(&mut self) {
+        self.nodes.clear();
+        self.recent.clear();
+    }
+
+    /// Get the world volume of the tree.
+    pub fn bounds(&mut self) -> io::Result<Aabb3<S>> {
+        let root = self.root;
+        Ok(self.node(root)?.bounds)
+    }
+
+    /// Find all items whose volume overlaps `volume`, see `Octree::overlapping`.
+    pub fn overlapping<V>(&mut self, volume: V) -> io::Result<Vec<Item<S, K>>>
+        where V: Overlap<S>
+    {
+        let mut found = Vec::new();
+        let mut seen = HashSet::new();
+        let mut visited = 0;
+        let root = self.root;
+        let mut stack = match self.node(root)?.content {
+            Some(content) if volume.may_overlap_aabb(&content) => vec![root],
+            _ => Vec::new(),
+        };
+
+        while let Some(offset) = stack.pop() {
+            self.visit(&mut visited)?;
+            let unique = self.unique;
+            let node = self.node(offset)?;
+            for item in &node.items {
+                if volume.overlaps_aabb(&item.bb) && (!unique || seen.insert(item.id.clone())) {
+                    found.push(item.clone());
+                }
+            }
+
+            // children are pushed in reverse, so that they are searched in octant order
+            for child in node.children.iter().rev() {
+                if let Some(content) = child.content {
+                    if volume.may_overlap_aabb(&content) {
+                        stack.push(child.offset);
+                    }
+                }
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Find the nearest item for which `hit` returns a distance along `ray`, see
+    /// `Octree::ray_cast`.
+    ///
+    /// Only the nodes that the ray enters before the nearest hit found so far are loaded.
+    pub fn ray_cast<F>(&mut self, ray: Ray3<S>, mut hit: F) -> io::Result<Option<RayHit<S, K>>>
+        where F: FnMut(&Item<S, K>, &Ray3<S>) -> Option<S>
+    {
+        let mut best: Option<(Item<S, K>, S)> = None;
+        let mut visited = 0;
+        let mut stack = vec![(S::zero(), self.root)];
+
+        while let Some((t, offset)) = stack.pop() {
+            if !closer(t, &best) {
+                continue;
+            }
+
+            self.visit(&mut visited)?;
+            let node = self.node(offset)?;
+            for item in &node.items {
+                match ray_entry(&ray, &item.bb) {
+                    Some(t) if closer(t, &best) => {}
+                    _ => continue,
+                }
+
+                if let Some(t) = hit(item, &ray) {
+                    if closer(t, &best) {
+                        best = Some((item.clone(), t));
+                    }
+                }
+            }
+
+            // children are pushed farthest first, so that the nearest one is searched next
+            let mut entries = Vec::with_capacity(node.children.len());
+            for child in &node.children {
+                if let Some(t) = child.content.and_then(|content| ray_entry(&ray, &content)) {
+                    entries.push((t, child.offset));
+                }
+            }
+            entries.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+            stack.extend(entries);
+        }
+
+        Ok(best.map(|(item, t)| (ray.origin + ray.direction * t, item)))
+    }
+
+    /// Count a node visited by a query, which reaches every node at most once in a valid
+    /// file.
+    fn visit(&self, visited: &mut usize) -> io::Result<()> {
+        *visited += 1;
+        if *visited > self.node_count {
+            return invalid("node is referenced more than once");
+        }
+        Ok(())
+    }
+
+    /// Get the node at `offset`, loading it if needed.
+    fn node(&mut self, offset: u64) -> io::Result<&FileNode<S, K>> {
+        self.time += 1;
+        let time = self.time;
+        let used = match self.nodes.get_mut(&offset) {
+            Some(&mut (_, ref mut used)) => Some(mem::replace(used, time)),
+            None => None,
+        };
+
+        match used {
+            Some(used) => {
+                self.recent.remove(&used);
+            }
+            None => {
+                let node = self.load_node(offset)?;
+                let limit = self.cache_limit.max(1);
+                self.evict(limit - 1);
+                self.nodes.insert(offset, (node, time));
+            }
+        }
+        self.recent.insert(time, offset);
+
+        Ok(&self.nodes[&offset].0)
+    }
+
+    /// Forget the least recently used nodes until at most `limit` are loaded.
+    fn evict(&mut self, limit: usize) {
+        while self.nodes.len() > limit {
+            let oldest = match self.recent.keys().next() {
+                Some(&time) => time,
+                None => break,
+            };
+            let offset = self.recent.remove(&oldest).unwrap();
+            self.nodes.remove(&offset);
+        }
+    }
+
+    fn load_node(&mut self, offset: u64) -> io::Result<FileNode<S, K>> {
+        let header = self.read_bytes(offset, NODE_HEADER_SIZE)?;
+        let flags = get_u32(&header, BB_SIZE);
+        let item_count = get_u32(&header, BB_SIZE + 4) as usize;
+        let child_count = if flags & BRANCH != 0 { 8 } else { 0 };
+        if item_count > self.item_count {
+            return invalid("invalid node item count");
+        }
+
+        // nodes are written within a page if they fit into one, otherwise from a page start
+        let size = NODE_HEADER_SIZE + child_count * CHILD_SIZE + item_count * ITEM_SIZE;
+        let page_offset = (offset % self.page_size as u64) as usize;
+        if page_offset != 0 && page_offset + size > self.page_size {
+            return invalid("node crosses a page boundary");
+        }
+        let bytes = self.read_bytes(offset + NODE_HEADER_SIZE as u64,
+                                    size - NODE_HEADER_SIZE)?;
+
+        let end = offset + size as u64;
+        let mut children = Vec::with_capacity(child_count);
+        for i in 0..child_count {
+            let at = i * CHILD_SIZE;
+            let child = get_u64(&bytes, at);
+            // children are written after their parent, which also rules out cycles
+            if child < end || child >= self.len {
+                return invalid("invalid child offset");
+            }
+            children.push(FileChild {
+                offset: child,
+                content: get_content(&bytes, get_u32(&bytes, at + 8), at + 16),
+            });
+        }
+
+        let mut items = Vec::with_capacity(item_count);
+        for i in 0..item_count {
+            let at = child_count * CHILD_SIZE + i * ITEM_SIZE;
+            let id = match K::from_file_key(get_u64(&bytes, at)) {
+                Some(id) => id,
+                None => return invalid("item id is out of range"),
+            };
+            items.push(Item {
+                id: id,
+                bb: get_bb(&bytes, at + 8),
+            });
+        }
+
+        Ok(FileNode {
+            bounds: get_bb(&header, 0),
+            content: get_content(&header, flags, BB_SIZE + 8),
+            items: items,
+            children: children,
+        })
+    }
+
+    /// Read `len` bytes at `offset`.
+    fn read_bytes(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        if offset.saturating_add(len as u64) > self.len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "node is cut off"));
+        }
+
+        let mut bytes = vec![0; len];
+        self.input.seek(SeekFrom::Start(offset))?;
+        self.input.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+fn closer<S, K>(t: S, best: &Option<(Item<S, K>, S)>) -> bool
+    where S: BaseFloat
+{
+    is_closer(t, best.as_ref().map(|best| ((), best.1)))
+}
+
+fn invalid<T>(message: &str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    for i in 0..4 {
+        out.push((value >> (i * 8)) as u8);
+    }
+}
+
+fn put_u64(out: &mut Vec<u8>, value: u64) {
+    for i in 0..8 {
+        out.push((value >> (i * 8)) as u8);
+    }
+}
+
+fn put_bb<S>(out: &mut Vec<u8>, bb: &Aabb3<S>)
+    where S: BaseNum
+{
+    for point in &[bb.min, bb.max] {
+        for axis in 0..3 {
+            put_u64(out, point[axis].to_f64().unwrap_or(f64::NAN).to_bits());
+        }
+    }
+}
+
+fn put_content<S>(out: &mut Vec<u8>, content: &Option<Aabb3<S>>)
+    where S: BaseNum
+{
+    match *content {
+        Some(ref content) => put_bb(out, content),
+        None => out.extend_from_slice(&[0; BB_SIZE]),
+    }
+}
+
+fn get_u32(bytes: &[u8], at: usize) -> u32 {
+    (0..4).fold(0, |value, i| value | (bytes[at + i] as u32) << (i * 8))
+}
+
+fn get_u64(bytes: &[u8], at: usize) -> u64 {
+    (0..8).fold(0, |value, i| value | (bytes[at + i] as u64) << (i * 8))
+}
+
+fn get_bb<S>(bytes: &[u8], at: usize) -> Aabb3<S>
+    where S: BaseNum
+{
+    let coord = |i: usize| S::from(f64::from_bits(get_u64(bytes, at + i * 8))).unwrap();
+    Aabb3::new(Point3::new(coord(0), coord(1), coord(2)),
+               Point3::new(coord(3), coord(4), coord(5)))
+}
+
+fn get_content<S>(bytes: &[u8], flags: u32, at: usize) -> Option<Aabb3<S>>
+    where S: BaseNum
+{
+    if flags & HAS_CONTENT != 0 {
+        Some(get_bb(bytes, at))
+    } else {
+        None
+    }
+}
@@ -0,0 +1,129 @@
+//! Index from item ids to the nodes that store them.
+
+use std::hash::{BuildHasher, Hash};
+use prelude::*;
+use collections::HashMap;
+
+/// Item id that is a small integer, such as an entity id, usable as a slot in a vector.
+///
+/// See `Octree::with_dense_keys`.
+pub trait DenseKey {
+    /// Slot of the id, distinct ids must have distinct slots.
+    fn dense_index(&self) -> usize;
+}
+
+macro_rules! impl_dense_key {
+    ($($t:ty),*) => {
+        $(
+            impl DenseKey for $t {
+                #[inline]
+                fn dense_index(&self) -> usize {
+                    *self as usize
+                }
+            }
+        )*
+    }
+}
+
+impl_dense_key!(u8, u16, u32, usize);
+
+/// Map from item ids to values, either hashed with `H` or stored in a vector slot.
+pub enum KeyIndex<K, V, H> {
+    Hashed(HashMap<K, V, H>),
+    Dense(DenseSlots<V>, fn(&K) -> usize),
+}
+
+pub struct DenseSlots<V> {
+    slots: Vec<Option<V>>,
+    len: usize,
+}
+
+impl<K, V, H> KeyIndex<K, V, H>
+    where K: Eq + Hash,
+          V: Copy,
+          H: BuildHasher
+{
+    pub fn hashed(hash_builder: H) -> KeyIndex<K, V, H> {
+        KeyIndex::Hashed(HashMap::with_hasher(hash_builder))
+    }
+
+    pub fn dense() -> KeyIndex<K, V, H>
+        where K: DenseKey
+    {
+        KeyIndex::Dense(DenseSlots {
+                            slots: Vec::new(),
+                            len: 0,
+                        },
+                        K::dense_index)
+    }
+
+    /// Create an empty index of the same kind.
+    pub fn empty_like(&self) -> KeyIndex<K, V, H>
+        where H: Clone
+    {
+        match *self {
+            KeyIndex::Hashed(ref map) => KeyIndex::hashed(map.hasher().clone()),
+            KeyIndex::Dense(_, index) => {
+                KeyIndex::Dense(DenseSlots {
+                                    slots: Vec::new(),
+                                    len: 0,
+                                },
+                                index)
+            }
+        }
+    }
+
+    pub fn get(&self, id: &K) -> Option<V> {
+        match *self {
+            KeyIndex::Hashed(ref map) => map.get(id).cloned(),
+            KeyIndex::Dense(ref dense, index) => {
+                dense.slots.get(index(id)).and_then(|slot| *slot)
+            }
+        }
+    }
+
+    pub fn contains_key(&self, id: &K) -> bool {
+        self.get(id).is_some()
+    }
+
+    /// Insert value, returning the previous one.
+    pub fn insert(&mut self, id: K, value: V) -> Option<V> {
+        match *self {
+            KeyIndex::Hashed(ref mut map) => map.insert(id, value),
+            KeyIndex::Dense(ref mut dense, index) => {
+                let i = index(&id);
+                if i >= dense.slots.len() {
+                    let missing = i + 1 - dense.slots.len();
+                    dense.slots.extend((0..missing).map(|_| None));
+                }
+
+                let previous = dense.slots[i].take();
+                dense.slots[i] = Some(value);
+                if previous.is_none() {
+                    dense.len += 1;
+                }
+                previous
+            }
+        }
+    }
+
+    pub fn remove(&mut self, id: &K) -> Option<V> {
+        match *self {
+            KeyIndex::Hashed(ref mut map) => map.remove(id),
+            KeyIndex::Dense(ref mut dense, index) => {
+                let previous = dense.slots.get_mut(index(id)).and_then(|slot| slot.take());
+                if previous.is_some() {
+                    dense.len -= 1;
+                }
+                previous
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match *self {
+            KeyIndex::Hashed(ref map) => map.is_empty(),
+            KeyIndex::Dense(ref dense, _) => dense.len == 0,
+        }
+    }
+}
@@ -1,5 +1,7 @@
+use collections::HashSet;
 use std::hash::Hash;
 use std::fmt;
+use prelude::*;
 use cgmath::*;
 use Item;
 use super::super::iterator;
@@ -11,19 +13,23 @@ pub struct OctreeIter<'a, S: 'a, K: 'a>
           K: Clone + Eq + Hash
 {
     stack: Vec<iterator::State<'a, S, K>>,
+    seen: Option<HashSet<K>>,
 }
 
 impl<'a, S: 'a, K: 'a> OctreeIter<'a, S, K>
     where S: BaseNum,
           K: Clone + Eq + Hash
 {
-    pub fn new<'r>(node: &'r Node<S, K>) -> OctreeIter<'r, S, K> {
+    /// Create iterator over items of `node` subtree, `unique` skips repeated references to
+    /// the same item.
+    pub fn new<'r>(node: &'r Node<S, K>, unique: bool) -> OctreeIter<'r, S, K> {
         OctreeIter {
             stack: vec![iterator::State {
                 node: node,
                 leaf_index: 0,
                 node_index: 0,
             }],
+            seen: if unique { Some(HashSet::new()) } else { None },
         }
     }
 }
@@ -45,6 +51,12 @@ impl<'a, S: 'a, K: 'a> Iterator for OctreeIter<'a, S, K>
                     let leaf = unsafe { node.leafs.get_unchecked(*leaf_index) };
                     *leaf_index += 1;
 
+                    if let Some(ref mut seen) = self.seen {
+                        if !seen.insert(leaf.id.clone()) {
+                            continue;
+                        }
+                    }
+
                     return Some(leaf);
                 } else {
                     // node iteration state, does not produce leafs until
@@ -1,5 +1,6 @@
 use std::hash::Hash;
 use std::fmt;
+use prelude::*;
 use cgmath::*;
 use collision::*;
 use Item;
@@ -30,7 +31,7 @@ pub struct DebugIter<'a, S: 'a, K: 'a>
 }
 
 impl<'a, S: 'a, K: 'a> DebugIter<'a, S, K>
-    where S: BaseNum + BaseFloat,
+    where S: BaseNum,
           K: Clone + Eq + Hash
 {
     pub fn new<'r>(node: &'r Node<S, K>) -> DebugIter<'r, S, K> {
@@ -44,7 +45,7 @@ impl<'a, S: 'a, K: 'a> DebugIter<'a, S, K>
     }
 }
 
-fn calculate_goodness(depth: usize) -> f32 {
+pub fn calculate_goodness(depth: usize) -> f32 {
     let mut fdepth = depth as f32;
 
     let good_val = 10.0;
@@ -61,15 +62,15 @@ fn calculate_goodness(depth: usize) -> f32 {
 }
 
 impl<'a, S: 'a, K: 'a> Iterator for DebugIter<'a, S, K>
-    where S: BaseNum + BaseFloat,
+    where S: BaseNum,
           K: Clone + Eq + Hash + fmt::Debug
 {
     type Item = DebugItem<'a, S, K>;
 
     fn next<'i>(&'i mut self) -> Option<DebugItem<'a, S, K>> {
-        let stack_depth = self.stack.len();
-
         while !self.stack.is_empty() {
+            let stack_depth = self.stack.len();
+
             let iter_action = {
                 let &mut iterator::State { ref node, ref mut leaf_index, ref mut node_index } =
                     self.stack.last_mut().unwrap();
@@ -0,0 +1,106 @@
+use collections::HashSet;
+use std::hash::Hash;
+use std::slice;
+use prelude::*;
+use cgmath::*;
+use collision::*;
+use Item;
+use NodeRef;
+use query;
+
+/// Item or group of items selected by `Octree::select_lod`.
+pub enum Lod<'a, S: 'a, K: 'a>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    /// Item that is large enough on screen to be drawn on its own.
+    Item(&'a Item<S, K>),
+    /// Node whose items are small enough on screen to be drawn as one aggregate proxy
+    /// covering `NodeRef::content_bounds`.
+    Proxy(NodeRef<'a, S, K>),
+}
+
+/// Iterator over the level of detail selection, see `Octree::select_lod`.
+pub struct LodIter<'a, S: 'a, K: 'a>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    camera: Point3<S>,
+    threshold: S,
+    stack: Vec<NodeRef<'a, S, K>>,
+    items: slice::Iter<'a, Item<S, K>>,
+    seen: Option<HashSet<K>>,
+}
+
+impl<'a, S: 'a, K: 'a> LodIter<'a, S, K>
+    where S: BaseFloat,
+          K: Clone + Eq + Hash
+{
+    /// Create iterator over the `node` subtree as seen from `camera`, `unique` skips
+    /// repeated references to the same item.
+    pub fn new<'r>(camera: Point3<S>,
+                   threshold: S,
+                   node: NodeRef<'r, S, K>,
+                   unique: bool)
+                   -> LodIter<'r, S, K> {
+        LodIter {
+            camera: camera,
+            threshold: threshold,
+            stack: vec![node],
+            items: [].iter(),
+            seen: if unique { Some(HashSet::new()) } else { None },
+        }
+    }
+
+    /// Returns `true` if the size of `content` divided by its distance from the camera is
+    /// within the threshold.
+    fn is_coarse_enough(&self, content: &Aabb3<S>) -> bool {
+        let size2 = (content.max - content.min).magnitude2();
+        let distance2 = query::distance2(content, self.camera);
+        size2 <= self.threshold * self.threshold * distance2
+    }
+}
+
+impl<'a, S: 'a, K: 'a> Iterator for LodIter<'a, S, K>
+    where S: BaseFloat,
+          K: Clone + Eq + Hash
+{
+    type Item = Lod<'a, S, K>;
+
+    fn next(&mut self) -> Option<Lod<'a, S, K>> {
+        loop {
+            if let Some(item) = self.items.next() {
+                if let Some(ref mut seen) = self.seen {
+                    if !seen.insert(item.id.clone()) {
+                        continue;
+                    }
+                }
+
+                return Some(Lod::Item(item));
+            }
+
+            let node = self.stack.pop()?;
+
+            let content = match node.content_bounds() {
+                Some(content) => content,
+                None => continue,
+            };
+            if !node.is_branch() && node.items().is_empty() {
+                // left behind by a removal when the bounds are not kept tight
+                continue;
+            }
+
+            if self.is_coarse_enough(&content) {
+                return Some(Lod::Proxy(node));
+            }
+
+            // children are popped in octant order, after the items of this node
+            self.items = node.items().iter();
+            for i in (0..8).rev() {
+                if let Some(child) = node.child(i) {
+                    self.stack.push(child);
+                }
+            }
+        }
+    }
+}
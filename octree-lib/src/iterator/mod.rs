@@ -4,7 +4,9 @@ use super::node::Node;
 
 pub mod all;
 pub mod ray_intersection;
+pub mod overlap;
 pub mod debug;
+pub mod lod;
 
 pub struct State<'a, S: 'a, K: 'a>
     where S: BaseNum,
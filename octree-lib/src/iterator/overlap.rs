@@ -0,0 +1,103 @@
+use collections::HashSet;
+use std::hash::Hash;
+use prelude::*;
+use cgmath::*;
+use Item;
+use shape::Overlap;
+use super::super::iterator;
+use super::super::node::Node;
+
+/// Iterator over items that overlap a volume.
+pub struct OverlapIter<'a, S: 'a, K: 'a, V>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    volume: V,
+    stack: Vec<iterator::State<'a, S, K>>,
+    seen: Option<HashSet<K>>,
+}
+
+impl<'a, S: 'a, K: 'a, V> OverlapIter<'a, S, K, V>
+    where S: BaseNum,
+          K: Clone + Eq + Hash,
+          V: Overlap<S>
+{
+    /// Create iterator over items of `node` subtree that overlap `volume`, `unique` skips
+    /// repeated references to the same item.
+    pub fn new<'r>(volume: V, node: &'r Node<S, K>, unique: bool) -> OverlapIter<'r, S, K, V> {
+        OverlapIter {
+            volume: volume,
+            stack: vec![iterator::State {
+                node: node,
+                leaf_index: 0,
+                node_index: 0,
+            }],
+            seen: if unique { Some(HashSet::new()) } else { None },
+        }
+    }
+}
+
+impl<'a, S: 'a, K: 'a, V> Iterator for OverlapIter<'a, S, K, V>
+    where S: BaseNum,
+          K: Clone + Eq + Hash,
+          V: Overlap<S>
+{
+    type Item = &'a Item<S, K>;
+
+    fn next(&mut self) -> Option<&'a Item<S, K>> {
+        while !self.stack.is_empty() {
+            let iter_action = {
+                let &mut iterator::State { node, ref mut leaf_index, ref mut node_index } =
+                    self.stack.last_mut().unwrap();
+
+                if *leaf_index < node.leafs.len() {
+                    let leaf_candidate = &node.leafs[*leaf_index];
+                    *leaf_index += 1;
+
+                    if let Some(ref seen) = self.seen {
+                        if seen.contains(&leaf_candidate.id) {
+                            continue;
+                        }
+                    }
+
+                    if self.volume.overlaps_aabb(&leaf_candidate.bb) {
+                        if let Some(ref mut seen) = self.seen {
+                            seen.insert(leaf_candidate.id.clone());
+                        }
+
+                        return Some(leaf_candidate);
+                    }
+
+                    continue;
+                } else if node.is_branch && *node_index < 8 {
+                    let node_candidate = node.get_node(*node_index);
+                    *node_index += 1;
+
+                    match node_candidate.content {
+                        Some(ref content) if self.volume.may_overlap_aabb(content) => {}
+                        _ => continue,
+                    }
+
+                    iterator::Action::Push(node_candidate)
+                } else {
+                    iterator::Action::Pop
+                }
+            };
+
+            match iter_action {
+                iterator::Action::Pop => {
+                    self.stack.pop();
+                }
+                iterator::Action::Push(node) => {
+                    self.stack.push(iterator::State {
+                        node: node,
+                        leaf_index: 0,
+                        node_index: 0,
+                    });
+                }
+            };
+        }
+
+        None
+    }
+}
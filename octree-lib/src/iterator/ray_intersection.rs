@@ -1,10 +1,13 @@
+use collections::HashSet;
 use std::hash::Hash;
 use std::fmt;
+use prelude::*;
 use cgmath::*;
 use collision::*;
 use Item;
 use super::super::iterator;
 use super::super::node::Node;
+use query;
 
 /// Ray intersection with the item.
 pub struct RayIntersection<'a, S: 'a, K: 'a> {
@@ -15,6 +18,14 @@ pub struct RayIntersection<'a, S: 'a, K: 'a> {
 impl<'a, S: 'a, K: 'a> RayIntersection<'a, S, K>
     where S: Clone
 {
+    /// Create intersection of `item` at `point`.
+    pub fn new(point: Point3<S>, item: &'a Item<S, K>) -> RayIntersection<'a, S, K> {
+        RayIntersection {
+            hit_point: point,
+            hit_item: item,
+        }
+    }
+
     /// Retrieve the intersection point.
     pub fn point(&self) -> Point3<S> {
         self.hit_point.clone()
@@ -33,13 +44,19 @@ pub struct RayIntersectionsIter<'a, S: 'a, K: 'a>
 {
     ray: Ray3<S>,
     stack: Vec<iterator::State<'a, S, K>>,
+    seen: Option<HashSet<K>>,
 }
 
 impl<'a, S: 'a, K: 'a> RayIntersectionsIter<'a, S, K>
     where S: BaseNum + BaseFloat,
           K: Clone + Eq + Hash
 {
-    pub fn new<'r>(ray: Ray3<S>, node: &'r Node<S, K>) -> RayIntersectionsIter<'r, S, K> {
+    /// Create iterator over items of `node` subtree hit by `ray`, `unique` skips repeated
+    /// references to the same item.
+    pub fn new<'r>(ray: Ray3<S>,
+                   node: &'r Node<S, K>,
+                   unique: bool)
+                   -> RayIntersectionsIter<'r, S, K> {
         RayIntersectionsIter {
             ray: ray,
             stack: vec![iterator::State {
@@ -47,6 +64,7 @@ impl<'a, S: 'a, K: 'a> RayIntersectionsIter<'a, S, K>
                 leaf_index: 0,
                 node_index: 0,
             }],
+            seen: if unique { Some(HashSet::new()) } else { None },
         }
     }
 }
@@ -68,7 +86,17 @@ impl<'a, S: 'a, K: 'a> Iterator for RayIntersectionsIter<'a, S, K>
                     let leaf_candidate = unsafe { node.leafs.get_unchecked(*leaf_index) };
                     *leaf_index += 1;
 
+                    if let Some(ref seen) = self.seen {
+                        if seen.contains(&leaf_candidate.id) {
+                            continue;
+                        }
+                    }
+
                     if let Some(point) = (self.ray, leaf_candidate.bb).intersection() {
+                        if let Some(ref mut seen) = self.seen {
+                            seen.insert(leaf_candidate.id.clone());
+                        }
+
                         // println!("ok! {:?}", point);
                         return Some(RayIntersection {
                             hit_point: point,
@@ -86,11 +114,9 @@ impl<'a, S: 'a, K: 'a> Iterator for RayIntersectionsIter<'a, S, K>
 
                         *node_index += 1;
 
-                        if node_candidate.is_empty() {
-                            continue;
-                        }
-
-                        if let None = (self.ray, node_candidate.bb).intersection() {
+                        let hit = node_candidate.content
+                            .and_then(|content| query::ray_entry(&self.ray, &content));
+                        if hit.is_none() {
                             continue;
                         }
 
@@ -5,67 +5,253 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+//! Bounded octree for spatial partitioning.
+//!
+//! The `hashbrown` feature replaces the `std` hash maps used for the item index and the
+//! queries with the ones from the `hashbrown` crate. With it, the default `std` feature can
+//! be disabled, and the crate itself then uses only `core` and `alloc`, without the
+//! `export` and `file` modules. Note that `cgmath` 0.10 and `collision` 0.8 still link
+//! `std`, so targets without `std` are not supported until the math crates are.
+//!
+//! There is no `BTreeMap` item index for `K: Ord` ids: the iterators deduplicate straddling
+//! items and the trackers compare overlaps with hash sets, so every tree needs `K: Hash`
+//! regardless of the index.
+//!
+//! The `point-cloud` feature adds the `point_cloud` module for importing and filtering
+//! scanned point clouds.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(not(feature = "std"))]
+extern crate core as std;
+#[cfg_attr(not(feature = "std"), macro_use)]
+extern crate alloc;
+#[cfg(feature = "hashbrown")]
+extern crate hashbrown;
 extern crate cgmath;
 extern crate collision;
 
+#[cfg(not(any(feature = "std", feature = "hashbrown")))]
+compile_error!("either the `std` or the `hashbrown` feature must be enabled");
+
+mod prelude;
+mod collections;
 mod node;
 mod item;
 mod iterator;
+mod error;
+mod scalar;
+mod node_ref;
+mod visitor;
+mod event;
+mod mode;
+mod query;
+mod shape;
+mod rebalance;
+mod index;
+mod snapshot;
+#[cfg(feature = "std")]
+pub mod export;
+#[cfg(feature = "std")]
+pub mod file;
+#[cfg(feature = "point-cloud")]
+pub mod point_cloud;
 
 pub use item::Item;
+pub use error::{Error, Result};
+pub use scalar::Scalar;
+pub use node_ref::{NodeRef, Children, Face};
+pub use visitor::{Visit, Visitor};
+pub use event::{Event, NodeId};
+pub use mode::StraddleMode;
+pub use query::closest::ClosestItem;
+pub use query::tracker::{OverlapTracker, OverlapChanges};
 pub use iterator::all::OctreeIter;
 pub use iterator::debug::{DebugIter, DebugItem};
 pub use iterator::ray_intersection::{RayIntersection, RayIntersectionsIter};
+pub use iterator::overlap::OverlapIter;
+pub use iterator::lod::{Lod, LodIter};
+pub use shape::{Overlap, Capsule, OrientedBox};
+pub use index::DenseKey;
+pub use snapshot::{Snapshot, SnapshotIter};
 
-use std::collections::HashMap;
-use std::collections::hash_map::Entry;
 use std::cmp::Eq;
-use std::hash::Hash;
+use std::hash::{Hash, BuildHasher};
 use std::fmt;
-use node::Node;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use prelude::*;
+use node::{Node, Context};
+use index::KeyIndex;
+use collections::RandomState;
+use snapshot::SnapshotNode;
+use alloc::sync::Arc;
 use cgmath::*;
 use collision::*;
 
 /// Hierarchical storage of items sorted by location in subdivided 3D space.
-pub struct Octree<S, K>
+///
+/// Coordinates can be floating point or integer, see `Scalar` for how integer volumes
+/// are subdivided.
+///
+/// # Iteration order
+///
+/// Iteration over the tree, `overlapping`, `ray_intersections`, `select_lod` and `visit`
+/// walk the tree depth-first, yielding the items stored in a node before descending into
+/// its children, and visiting children in octant order, see `NodeRef::octant`. Items
+/// stored in the same node keep the order in which they were added to it, and removing an
+/// item does not reorder the rest, so the same sequence of modifications always produces
+/// the same order. Updating an item moves it to the end of its node only if it moves to a
+/// different node.
+///
+/// `ray_cast`, `ray_packet`, `closest_item` and `visible_items` search nearest first
+/// instead, and the order among items at the same distance is not specified.
+///
+/// # Item index
+///
+/// The node of every item is looked up by id when it is updated or removed. By default the
+/// ids are hashed with `H`, see `with_hasher` for choosing a faster hasher. Small integer
+/// ids, such as entity ids, can index a vector directly instead, see `with_dense_keys`.
+pub struct Octree<S, K, H = RandomState>
     where S: BaseNum,
           K: Clone + Eq + Hash
 {
-    branch_size: usize,
     root: Box<Node<S, K>>,
-    object_node: HashMap<K, *mut Node<S, K>>,
+    ctx: Context<S, K, H>,
+    rebalance: rebalance::Cursor,
+    /// Changed by every `&mut self` method that modifies the structure. Such a method must
+    /// take a new revision whenever it may free, pool or move a node, since `OverlapTracker`
+    /// keeps a raw pointer to a node and dereferences it only while the revision is the same.
+    revision: usize,
+    /// Nodes of the last snapshot, shared with the unchanged nodes of the next one.
+    snapshot: Option<Arc<SnapshotNode<S, K>>>,
+}
+
+/// Source of tree revisions, shared by all trees so that revisions are never reused.
+static NEXT_REVISION: AtomicUsize = AtomicUsize::new(0);
+
+fn next_revision() -> usize {
+    NEXT_REVISION.fetch_add(1, Ordering::Relaxed)
 }
 
 impl<S, K> Octree<S, K>
     where K: Clone + Eq + Hash,
-          S: BaseFloat
+          S: Scalar
 {
     /// Create a new tree with default branch size of `16` and the world size enclosed by `bb`
     /// volume.
+    ///
+    /// Panics if `bb` is not a valid world volume, see `try_new`.
     pub fn new(bb: Aabb3<S>) -> Octree<S, K> {
-        assert!(bb.volume() > S::zero());
+        Octree::try_new(bb).expect("invalid octree world volume")
+    }
 
-        // println!("new Octree {:?}", bb);
+    /// Create a new tree with specified branch size and world size enclosed by `bb` volume.
+    ///
+    /// Panics if `branch_size` is zero or `bb` is not a valid world volume, see
+    /// `try_with_branch_size`.
+    pub fn with_branch_size(branch_size: usize, bb: Aabb3<S>) -> Octree<S, K> {
+        Octree::try_with_branch_size(branch_size, bb).expect("invalid octree configuration")
+    }
 
-        Octree {
-            branch_size: 16,
-            root: Box::new(Node::new(bb)),
-            object_node: HashMap::new(),
-        }
+    /// Create a new tree with default branch size of `16` and the world size enclosed by `bb`
+    /// volume.
+    ///
+    /// Returns an error if `bb` has non-finite coordinates, zero volume or an extent that does
+    /// not fit into `S`.
+    pub fn try_new(bb: Aabb3<S>) -> Result<Octree<S, K>> {
+        Octree::try_with_branch_size(16, bb)
     }
 
     /// Create a new tree with specified branch size and world size enclosed by `bb` volume.
-    pub fn with_branch_size(branch_size: usize, bb: Aabb3<S>) -> Octree<S, K> {
-        assert!(branch_size > 0);
-        assert!(bb.volume() > S::zero());
+    ///
+    /// Returns an error if `branch_size` is zero, or `bb` has non-finite coordinates, zero
+    /// volume or an extent that does not fit into `S`.
+    pub fn try_with_branch_size(branch_size: usize, bb: Aabb3<S>) -> Result<Octree<S, K>> {
+        Octree::try_with_hasher(branch_size, bb, RandomState::default())
+    }
+
+    /// Create a new tree that finds the node of an item by using the id as an index into a
+    /// vector, instead of hashing it.
+    ///
+    /// The vector grows to the largest id ever inserted, so ids should be allocated densely
+    /// from zero.
+    ///
+    /// Panics if `branch_size` is zero or `bb` is not a valid world volume, see
+    /// `try_with_dense_keys`.
+    pub fn with_dense_keys(branch_size: usize, bb: Aabb3<S>) -> Octree<S, K>
+        where K: DenseKey
+    {
+        Octree::try_with_dense_keys(branch_size, bb).expect("invalid octree configuration")
+    }
+
+    /// Create a new tree that finds the node of an item by using the id as an index into a
+    /// vector, see `with_dense_keys`.
+    ///
+    /// Returns an error if `branch_size` is zero, or `bb` has non-finite coordinates, zero
+    /// volume or an extent that does not fit into `S`.
+    pub fn try_with_dense_keys(branch_size: usize, bb: Aabb3<S>) -> Result<Octree<S, K>>
+        where K: DenseKey
+    {
+        Octree::with_index(branch_size, bb, KeyIndex::dense())
+    }
+}
+
+impl<S, K, H> Octree<S, K, H>
+    where K: Clone + Eq + Hash,
+          S: Scalar,
+          H: BuildHasher
+{
+    /// Create a new tree with specified branch size and world size enclosed by `bb` volume,
+    /// hashing item ids with `hash_builder`.
+    ///
+    /// Panics if `branch_size` is zero or `bb` is not a valid world volume, see
+    /// `try_with_hasher`.
+    pub fn with_hasher(branch_size: usize, bb: Aabb3<S>, hash_builder: H) -> Octree<S, K, H> {
+        Octree::try_with_hasher(branch_size, bb, hash_builder)
+            .expect("invalid octree configuration")
+    }
 
-        // println!("new Octree {:?}", bb);
+    /// Create a new tree with specified branch size and world size enclosed by `bb` volume,
+    /// hashing item ids with `hash_builder`.
+    ///
+    /// Returns an error if `branch_size` is zero, or `bb` has non-finite coordinates, zero
+    /// volume or an extent that does not fit into `S`.
+    pub fn try_with_hasher(branch_size: usize,
+                           bb: Aabb3<S>,
+                           hash_builder: H)
+                           -> Result<Octree<S, K, H>> {
+        Octree::with_index(branch_size, bb, KeyIndex::hashed(hash_builder))
+    }
 
-        Octree {
-            branch_size: branch_size,
-            root: Box::new(Node::new(bb)),
-            object_node: HashMap::new(),
+    fn with_index(branch_size: usize,
+                  bb: Aabb3<S>,
+                  index: KeyIndex<K, *mut Node<S, K>, H>)
+                  -> Result<Octree<S, K, H>> {
+        if branch_size == 0 {
+            return Err(Error::ZeroBranchSize);
+        }
+        validate_bb(&bb)?;
+        if !(bb.min.x < bb.max.x && bb.min.y < bb.max.y && bb.min.z < bb.max.z) {
+            return Err(Error::EmptyWorld);
+        }
+        // node centers are computed from the extent, see `Scalar`
+        for axis in 0..3 {
+            if bb.max[axis].checked_sub_scalar(bb.min[axis]).is_none() {
+                return Err(Error::CoordinateOutOfRange);
+            }
         }
+
+        let mut ctx = Context::new(branch_size, index);
+        let root = ctx.alloc_node(bb);
+
+        Ok(Octree {
+            root: root,
+            ctx: ctx,
+            rebalance: rebalance::Cursor::new(),
+            revision: next_revision(),
+            snapshot: None,
+        })
     }
 
     /// Insert or update tree item's `bb` volume.
@@ -73,37 +259,357 @@ impl<S, K> Octree<S, K>
     /// If the item's `bb` volume is outside of the world, the item will still be added,
     /// however, the space for it will not be subdivided, and the item will always incur
     /// constant performance penalty.
+    ///
+    /// The `bb` is not validated, and NaN or inverted volumes will end up in arbitrary
+    /// nodes. Use `try_update` for data that is not known to be well-formed.
     pub fn update(&mut self, id: K, bb: Aabb3<S>)
         where K: fmt::Debug
     {
         // println!("update {:?}, {:?}", id, bb);
 
-        let maybe_node = self.object_node.get_mut(&id).map(|v| unsafe { v.as_mut() }.unwrap());
+        self.revision = next_revision();
+
+        if self.ctx.mode == StraddleMode::Children {
+            self.remove(id.clone());
+            self.root.insert_overlapping(id, bb, &mut self.ctx);
+            return;
+        }
+
+        let maybe_node = self.ctx.object_node.get(&id).map(|v| unsafe { v.as_mut() }.unwrap());
         if let Some(node) = maybe_node {
-            node.update(self.branch_size, id, bb, &mut self.object_node);
+            node.update(id, bb, &mut self.ctx);
             return;
         }
 
-        self.root.insert(self.branch_size, id, bb, &mut self.object_node);
+        self.root.insert(id, bb, &mut self.ctx);
+    }
+
+    /// Insert or update tree item's `bb` volume, rejecting volumes with NaN or infinite
+    /// coordinates, or with `min` greater than `max`.
+    ///
+    /// The tree is not modified if the volume is rejected.
+    pub fn try_update(&mut self, id: K, bb: Aabb3<S>) -> Result<()>
+        where K: fmt::Debug
+    {
+        validate_bb(&bb)?;
+        self.update(id, bb);
+        Ok(())
     }
 
     /// Remove tree item.
     pub fn remove(&mut self, id: K) -> Option<Item<S, K>> {
-        match self.object_node.entry(id.clone()) {
-            Entry::Occupied(mut e) => {
-                let leaf = unsafe { e.get_mut().as_mut() }.unwrap().remove(id);
-                e.remove();
-                leaf
+        let node = match self.ctx.object_node.remove(&id) {
+            Some(node) => unsafe { node.as_mut() }.unwrap(),
+            None => return None,
+        };
+
+        self.revision = next_revision();
+
+        if self.ctx.mode == StraddleMode::Children {
+            let bb = node.leafs
+                .iter()
+                .find(|leaf| leaf.id == id)
+                .expect("item is referenced from the indexed node")
+                .bb;
+            let leaf = self.root.remove_overlapping(&id, &bb, &mut self.ctx);
+            self.root.collapse_empty_overlapping(&bb, &mut self.ctx);
+            return leaf;
+        }
+
+        if self.ctx.is_recording() {
+            self.ctx.emit(Event::ItemRemoved {
+                id: id.clone(),
+                node: node.id,
+            });
+        }
+
+        node.remove(id, &mut self.ctx)
+    }
+
+    /// Placement policy of items that cross child node boundaries.
+    pub fn straddle_mode(&self) -> StraddleMode {
+        self.ctx.mode
+    }
+
+    /// Change the placement policy of items that cross child node boundaries.
+    ///
+    /// All existing items are re-inserted if the mode changes.
+    pub fn set_straddle_mode(&mut self, mode: StraddleMode)
+        where K: fmt::Debug
+    {
+        if self.ctx.mode == mode {
+            return;
+        }
+
+        let items: Vec<Item<S, K>> = self.into_iter().cloned().collect();
+        for item in &items {
+            self.remove(item.id.clone());
+        }
+
+        self.ctx.mode = mode;
+        for item in items {
+            self.update(item.id, item.bb);
+        }
+    }
+
+    /// Move all items of `other` tree into this tree.
+    ///
+    /// If both trees use `StraddleMode::Parent` with the same branch size, and the world of
+    /// `other` matches a node that this tree has or could subdivide into, and that node is
+    /// empty, the nodes of `other` are moved into place without re-inserting the items.
+    /// This is the case for streamed chunks aligned to the octant grid of the world.
+    /// Otherwise the items are inserted one by one.
+    ///
+    /// Returns an error without modifying the tree if any of the keys is already used.
+    pub fn merge(&mut self, mut other: Octree<S, K, H>) -> Result<()>
+        where K: fmt::Debug
+    {
+        if other.into_iter().any(|item| self.ctx.object_node.contains_key(&item.id)) {
+            return Err(Error::DuplicateKey);
+        }
+
+        self.ctx.pool.append(&mut other.ctx.pool);
+        self.revision = next_revision();
+
+        let can_graft = self.ctx.mode == StraddleMode::Parent &&
+                        other.ctx.mode == StraddleMode::Parent &&
+                        self.ctx.branch_size == other.ctx.branch_size;
+        if can_graft {
+            let bb = other.root.bb;
+            match self.root.find_empty_node(&bb, &mut self.ctx) {
+                Some(node) => {
+                    let node = unsafe { node.as_mut() }.unwrap();
+                    for item in node.adopt(&mut other.root, &mut self.ctx) {
+                        self.update(item.id, item.bb);
+                    }
+                    return Ok(());
+                }
+                None => self.root.collapse_empty_overlapping(&bb, &mut self.ctx),
             }
-            _ => None,
         }
+
+        let items: Vec<Item<S, K>> = other.into_iter().cloned().collect();
+        for item in items {
+            self.update(item.id, item.bb);
+        }
+
+        Ok(())
     }
 
-    /// Get iterator over all items that intersect the specified `ray`.
+    /// Move all items that are inside `region` to a new tree with `region` as its world,
+    /// using the same branch size, straddle mode and item index.
     ///
-    /// The item order is unspecified and can vary wildly between tree modifications.
-    pub fn ray_intersections<'a>(&'a self, ray: Ray3<S>) -> RayIntersectionsIter<'a, S, K> {
-        RayIntersectionsIter::new(ray, &self.root)
+    /// Returns an error if `region` is not a valid world volume.
+    pub fn split_off(&mut self, region: Aabb3<S>) -> Result<Octree<S, K, H>>
+        where K: fmt::Debug,
+              H: Clone
+    {
+        let index = self.ctx.object_node.empty_like();
+        let mut tree = Octree::with_index(self.ctx.branch_size, region, index)?;
+        tree.ctx.mode = self.ctx.mode;
+
+        let items: Vec<Item<S, K>> = self.overlapping(region)
+            .filter(|item| query::contains_bb(&region, &item.bb))
+            .cloned()
+            .collect();
+        for item in items {
+            self.remove(item.id.clone());
+            tree.update(item.id, item.bb);
+        }
+
+        Ok(tree)
+    }
+
+    /// Start or stop recording structural changes of the tree.
+    ///
+    /// When recording, every node subdivision and collapse, as well as item insertion,
+    /// removal and move to a different node is appended to the event log, which can be
+    /// retrieved with `take_events`. Stopping the recording discards the log.
+    pub fn record_events(&mut self, enabled: bool) {
+        if !enabled {
+            self.ctx.events = None;
+        } else if self.ctx.events.is_none() {
+            self.ctx.events = Some(Vec::new());
+        }
+    }
+
+    /// Take all events recorded since the last call, leaving the log empty.
+    pub fn take_events(&mut self) -> Vec<Event<K>> {
+        match self.ctx.events {
+            Some(ref mut events) => mem::take(events),
+            None => Vec::new(),
+        }
+    }
+
+    /// Move the coordinate origin to `offset`, subtracting it from the world bounds and
+    /// all stored item volumes.
+    ///
+    /// The tree structure is kept as is, which makes this suitable for periodically
+    /// rebasing a large world around the camera or player to keep coordinates small.
+    ///
+    /// With integer coordinates, the shifted world bounds and item volumes must fit into
+    /// `S`, otherwise the subtraction overflows. Use `try_shift_origin` if that is not
+    /// known in advance.
+    pub fn shift_origin(&mut self, offset: Vector3<S>) {
+        self.root.shift_origin(offset);
+        self.revision = next_revision();
+    }
+
+    /// Move the coordinate origin to `offset` like `shift_origin`, rejecting offsets that
+    /// would move the world bounds or an item volume out of the range of `S`, which for
+    /// floating point coordinates means to infinity.
+    ///
+    /// The tree is not modified if the offset is rejected.
+    pub fn try_shift_origin(&mut self, offset: Vector3<S>) -> Result<()> {
+        // subtraction keeps the order of coordinates, and every stored volume lies within
+        // the world bounds or the content bounds of the root, so checking these is enough
+        let mut extent = self.root.bb;
+        if let Some(content) = self.root.content {
+            extent = extent.grow(content.min).grow(content.max);
+        }
+        for corner in &[extent.min, extent.max] {
+            for axis in 0..3 {
+                // items stored without validation can already be non-finite
+                match corner[axis].checked_sub_scalar(offset[axis]) {
+                    Some(shifted) if shifted.is_finite_scalar() ||
+                                     !corner[axis].is_finite_scalar() => {}
+                    _ => return Err(Error::CoordinateOutOfRange),
+                }
+            }
+        }
+
+        self.shift_origin(offset);
+        Ok(())
+    }
+
+    /// Incrementally improve the tree structure, visiting at most `budget` nodes.
+    ///
+    /// The structure otherwise changes only as a side effect of item updates, so after
+    /// many items are removed or moved the tree can stay deeper than needed, or keep items
+    /// in nodes that could be subdivided. Every step continues where the previous one
+    /// stopped, collapsing nodes whose subtrees hold no more than `branch_size` items,
+    /// subdividing overfull nodes and moving items stuck in branch nodes down. Returns
+    /// `true` when a pass over the whole tree finished without finding anything to change.
+    ///
+    /// Content bounds of the visited nodes are recomputed too, if tight bounds are disabled.
+    pub fn rebalance_step(&mut self, budget: usize) -> bool {
+        let progress = rebalance::step(&mut self.root, &mut self.ctx, &mut self.rebalance, budget);
+        if progress.changed {
+            self.revision = next_revision();
+        }
+        progress.balanced
+    }
+
+    /// Choose whether the content bounds of nodes are recomputed when items are removed or
+    /// shrink, enabled by default.
+    ///
+    /// Queries skip nodes by the union of the item volumes in their subtree, see
+    /// `NodeRef::content_bounds`. Growing the bounds on insert is cheap, but recomputing them
+    /// on removal visits the items of the node and its ancestors. With tight bounds disabled
+    /// they only grow, which keeps queries correct but less selective, until `rebalance_step`
+    /// passes over the nodes or tight bounds are enabled again.
+    pub fn set_tight_bounds(&mut self, enabled: bool) {
+        if enabled && !self.ctx.tight_bounds {
+            self.root.tighten_content();
+        }
+        self.ctx.tight_bounds = enabled;
+    }
+
+    /// Returns `true` if content bounds are recomputed when items are removed or shrink.
+    pub fn tight_bounds(&self) -> bool {
+        self.ctx.tight_bounds
+    }
+
+    /// Make sure that at least `additional` nodes can be created without allocating.
+    ///
+    /// Nodes removed when the tree collapses are kept in a pool and reused by later
+    /// subdivisions, together with their item storage, so after a warm-up the updates of a
+    /// moving scene do not allocate. Every subdivision takes 8 nodes.
+    pub fn reserve_nodes(&mut self, additional: usize) {
+        let bb = self.root.bb;
+        let missing = additional.saturating_sub(self.ctx.pool.len());
+        self.ctx.pool.reserve(missing);
+        for _ in 0..missing {
+            self.ctx.pool.push(Box::new(Node::new(NodeId(0), bb)));
+        }
+    }
+
+    /// Free all pooled nodes.
+    pub fn shrink_to_fit(&mut self) {
+        self.ctx.pool = Vec::new();
+    }
+
+    /// Take an immutable snapshot of the tree, which can be read from other threads while
+    /// this tree is modified.
+    ///
+    /// Only the nodes changed since the previous snapshot are copied, the rest is shared
+    /// with it, so publishing a snapshot every frame costs about as much as the changes
+    /// made during the frame.
+    pub fn snapshot(&mut self) -> Snapshot<S, K> {
+        let root = snapshot::build(&mut self.root, self.snapshot.as_ref());
+        self.snapshot = Some(root.clone());
+        Snapshot::new(root, self.ctx.mode, self.revision)
+    }
+
+    /// Number that changes whenever the tree is modified.
+    ///
+    /// Revisions are never reused, even by different trees, so a cached query result is
+    /// valid as long as the revision it was computed for is the current one.
+    pub fn revision(&self) -> usize {
+        self.revision
+    }
+
+    /// Number of nodes in the tree, including the root node.
+    pub fn node_count(&self) -> usize {
+        self.ctx.live_nodes
+    }
+
+    /// Number of nodes kept for reuse, see `reserve_nodes`.
+    pub fn pooled_node_count(&self) -> usize {
+        self.ctx.pool.len()
+    }
+
+    /// Get read-only cursor to the root node.
+    pub fn root_node<'a>(&'a self) -> NodeRef<'a, S, K> {
+        NodeRef::new(&self.root, 0)
+    }
+
+    /// Get the leaf node containing `point`, `None` if the point is outside of the world.
+    ///
+    /// Points on the boundary between nodes belong to the node with the lower octant index.
+    /// Use `NodeRef::neighbors` to walk to the adjacent leaf nodes from there.
+    pub fn leaf_at<'a>(&'a self, point: Point3<S>) -> Option<NodeRef<'a, S, K>> {
+        let mut node = self.root_node();
+        if !query::contains_point(&node.bounds(), point) {
+            return None;
+        }
+
+        while node.is_branch() {
+            node = node.children()
+                .find(|child| query::contains_point(&child.bounds(), point))?;
+        }
+
+        Some(node)
+    }
+
+    /// Walk the tree with a custom `visitor`.
+    ///
+    /// Useful for implementing queries that are not provided by this crate.
+    pub fn visit<V>(&self, visitor: &mut V)
+        where V: Visitor<S, K> + ?Sized
+    {
+        visitor::walk(self.root_node(), visitor);
+    }
+
+    /// Get iterator over all items whose volume overlaps `volume`.
+    ///
+    /// Nodes are skipped with the conservative `Overlap::may_overlap_aabb` test of their
+    /// content bounds, items are checked with the exact one. Items are yielded in the tree
+    /// iteration order.
+    pub fn overlapping<'a, V>(&'a self, volume: V) -> OverlapIter<'a, S, K, V>
+        where V: Overlap<S>
+    {
+        OverlapIter::new(volume, &self.root, self.ctx.mode == StraddleMode::Children)
     }
 
     /// Get iterator over all items including the node volumes.
@@ -114,7 +620,114 @@ impl<S, K> Octree<S, K>
     }
 }
 
-impl<'a, S, K> IntoIterator for &'a Octree<S, K>
+impl<S, K, H> Octree<S, K, H>
+    where K: Clone + Eq + Hash,
+          S: Scalar + BaseFloat,
+          H: BuildHasher
+{
+    /// Get iterator over all items that intersect the specified `ray`.
+    ///
+    /// Items are yielded in the tree iteration order, not sorted by the distance.
+    pub fn ray_intersections<'a>(&'a self, ray: Ray3<S>) -> RayIntersectionsIter<'a, S, K> {
+        RayIntersectionsIter::new(ray, &self.root, self.ctx.mode == StraddleMode::Children)
+    }
+
+    /// Find the nearest item hit by each of the `rays`, in the same order as `rays`.
+    ///
+    /// The tree is traversed once for the whole packet, which is much faster than separate
+    /// `ray_intersections` queries for coherent rays, such as rays cast from one origin.
+    /// The intersection point is where the ray enters the item, or the ray origin if it
+    /// starts inside the item.
+    pub fn ray_packet<'a>(&'a self, rays: &[Ray3<S>]) -> Vec<Option<RayIntersection<'a, S, K>>> {
+        query::ray_packet::nearest(&self.root, rays)
+    }
+
+    /// Find the nearest item hit by `ray`, using `hit` as the exact intersection test.
+    ///
+    /// `hit` returns the ray parameter at which the ray hits the item, for example the
+    /// nearest triangle of a mesh, or `None` if it misses. It is called only for items whose
+    /// volume the ray enters before the nearest hit found so far, and the search stops at
+    /// nodes that the ray enters beyond it. The intersection point is at the returned ray
+    /// parameter.
+    pub fn ray_cast<'a, F>(&'a self, ray: Ray3<S>, hit: F) -> Option<RayIntersection<'a, S, K>>
+        where F: FnMut(&Item<S, K>, &Ray3<S>) -> Option<S>
+    {
+        query::ray_cast::nearest(&self.root, ray, hit)
+    }
+
+    /// Get iterator that selects the level of detail for drawing the items as seen from
+    /// `camera`.
+    ///
+    /// A node is yielded as a single `Lod::Proxy` if the size of its content bounds divided
+    /// by their distance from `camera` is at most `error_threshold`, which roughly is the
+    /// angle in radians the items cover on screen. Otherwise the items stored in the node
+    /// are yielded one by one and its children are checked in turn, in the tree iteration
+    /// order. With `StraddleMode::Children` an item can be covered by several proxies.
+    pub fn select_lod<'a>(&'a self, camera: Point3<S>, error_threshold: S) -> LodIter<'a, S, K> {
+        LodIter::new(camera,
+                     error_threshold,
+                     self.root_node(),
+                     self.ctx.mode == StraddleMode::Children)
+    }
+
+    /// Find the items visible from `viewpoint`, nearest first.
+    ///
+    /// Items for which `is_occluder` returns `true`, such as walls and terrain blocks, hide
+    /// the items and nodes behind them. Occlusion is tested against a coarse depth buffer
+    /// around the viewpoint with `resolution` cells along each edge of its 6 faces. The test
+    /// is conservative: an item that is only partly hidden, or hidden by several occluders
+    /// that each cover less than a cell, is reported visible.
+    pub fn visible_items<F>(&self,
+                            viewpoint: Point3<S>,
+                            resolution: usize,
+                            is_occluder: F)
+                            -> Vec<&Item<S, K>>
+        where F: FnMut(&Item<S, K>) -> bool
+    {
+        query::visibility::visible(&self.root,
+                                   viewpoint,
+                                   resolution,
+                                   self.ctx.mode == StraddleMode::Children,
+                                   is_occluder)
+    }
+
+    /// Find the item whose volume is closest to the `point`, together with the closest
+    /// point on it.
+    pub fn closest_item<'a>(&'a self, point: Point3<S>) -> Option<ClosestItem<'a, S, K>> {
+        query::closest::find(&self.root, point, None)
+    }
+
+    /// Get the distance from `point` to the nearest item volume, or `None` if there are no
+    /// items within `max` distance. A negative or NaN `max` finds nothing.
+    ///
+    /// Faster than `closest_item` with a small `max`, because the search skips all nodes
+    /// further than `max` from the point.
+    pub fn distance_to_nearest(&self, point: Point3<S>, max: S) -> Option<S> {
+        match max.partial_cmp(&S::zero()) {
+            Some(std::cmp::Ordering::Less) | None => return None,
+            _ => {}
+        }
+        query::closest::find(&self.root, point, Some(max * max)).map(|closest| closest.distance())
+    }
+}
+
+/// Check that `bb` has finite coordinates and is not inverted.
+fn validate_bb<S>(bb: &Aabb3<S>) -> Result<()>
+    where S: Scalar
+{
+    let coords = [bb.min.x, bb.min.y, bb.min.z, bb.max.x, bb.max.y, bb.max.z];
+    if coords.iter().any(|c| !c.is_finite_scalar()) {
+        return Err(Error::NonFiniteBounds);
+    }
+
+    if bb.min.x > bb.max.x || bb.min.y > bb.max.y || bb.min.z > bb.max.z {
+        return Err(Error::InvertedBounds);
+    }
+
+    Ok(())
+}
+
+impl<'a, S, K, H> IntoIterator for &'a Octree<S, K, H>
     where S: BaseNum,
           K: Clone + Eq + Hash + fmt::Debug
 {
@@ -122,207 +735,2019 @@ impl<'a, S, K> IntoIterator for &'a Octree<S, K>
     type IntoIter = OctreeIter<'a, S, K>;
 
     fn into_iter(self) -> OctreeIter<'a, S, K> {
-        OctreeIter::new(&self.root)
+        OctreeIter::new(&self.root, self.ctx.mode == StraddleMode::Children)
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use collision::*;
     use cgmath::*;
     use super::*;
+    use std::collections::HashMap;
 
     #[test]
     fn should_insert_if_not_fits_inside_world() {
-        let mut oc = Octree::with_branch_size(1,
-                                              Aabb3::new(Point3::new(0.0, 0.0, 0.0),
-                                                         Point3::new(10.0, 10.0, 10.0)));
-        oc.update(1,
-                  Aabb3::new(Point3::new(0.0, 0.0, 0.0), Point3::new(30.0, 30.0, 30.0)));
-
-        let list: Vec<_> = oc.into_iter().collect();
-        assert_eq!(list.len(), 1);
-        assert_eq!(list[0].id, 1);
+        for &mode in &MODES {
+            let mut oc = tree_with_mode(mode);
+            oc.update(1,
+                      Aabb3::new(Point3::new(0.0, 0.0, 0.0), Point3::new(30.0, 30.0, 30.0)));
+
+            let list: Vec<_> = oc.into_iter().collect();
+            assert_eq!(list.len(), 1);
+            assert_eq!(list[0].id, 1);
+        }
     }
 
     #[test]
     fn should_insert_if_fits_inside_world() {
-        let mut oc = Octree::with_branch_size(1,
-                                              Aabb3::new(Point3::new(0.0, 0.0, 0.0),
-                                                         Point3::new(10.0, 10.0, 10.0)));
-        oc.update(1,
-                  Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(5.0, 5.0, 5.0)));
-
-        let list: Vec<_> = oc.into_iter().collect();
-        assert_eq!(list.len(), 1);
-        assert_eq!(list[0].id, 1);
+        for &mode in &MODES {
+            let mut oc = tree_with_mode(mode);
+            oc.update(1,
+                      Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(5.0, 5.0, 5.0)));
+
+            let list: Vec<_> = oc.into_iter().collect();
+            assert_eq!(list.len(), 1);
+            assert_eq!(list[0].id, 1);
+        }
     }
 
     #[test]
     fn should_insert_and_subdivide() {
-        let mut oc = Octree::with_branch_size(1,
-                                              Aabb3::new(Point3::new(0.0, 0.0, 0.0),
-                                                         Point3::new(10.0, 10.0, 10.0)));
-        oc.update(1,
-                  Aabb3::new(Point3::new(2.0, 2.0, 2.0), Point3::new(5.0, 5.0, 5.0)));
+        for &mode in &MODES {
+            let mut oc = tree_with_mode(mode);
+            oc.update(1,
+                      Aabb3::new(Point3::new(2.0, 2.0, 2.0), Point3::new(5.0, 5.0, 5.0)));
 
-        oc.update(2,
-                  Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)));
+            oc.update(2,
+                      Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)));
 
-        let list: Vec<_> = oc.into_iter().collect();
-        assert_eq!(list.len(), 2);
+            let list: Vec<_> = oc.into_iter().collect();
+            assert_eq!(list.len(), 2);
 
-        assert_eq!(2,
-                   oc.ray_intersections(Ray3::new(Point3::new(0.0, 0.0, 0.0),
-                                                  vec3(1.0, 1.0, 1.0).normalize()))
-                       .count());
+            assert_eq!(2,
+                       oc.ray_intersections(Ray3::new(Point3::new(0.0, 0.0, 0.0),
+                                                      vec3(1.0, 1.0, 1.0).normalize()))
+                           .count());
 
-        assert_eq!(0,
-                   oc.ray_intersections(Ray3::new(Point3::new(0.0, 0.0, 0.0),
-                                                  vec3(1.0, 0.0, 0.0).normalize()))
-                       .count());
+            assert_eq!(0,
+                       oc.ray_intersections(Ray3::new(Point3::new(0.0, 0.0, 0.0),
+                                                      vec3(1.0, 0.0, 0.0).normalize()))
+                           .count());
 
-        let hits: Vec<_> = oc.ray_intersections(Ray3::new(Point3::new(1.5, 1.5, 0.0),
-                                                          vec3(0.0, 0.0, 1.0).normalize()))
-            .collect();
+            let hits: Vec<_> = oc.ray_intersections(Ray3::new(Point3::new(1.5, 1.5, 0.0),
+                                                              vec3(0.0, 0.0, 1.0).normalize()))
+                .collect();
 
-        assert_eq!(1, hits.len());
-        assert_eq!(hits[0].item().id, 2);
+            assert_eq!(1, hits.len());
+            assert_eq!(hits[0].item().id, 2);
 
-        let hits: Vec<_> = oc.ray_intersections(Ray3::new(Point3::new(3.0, 3.0, 0.0),
-                                                          vec3(0.001, 0.001, 1.0).normalize()))
-            .collect();
+            let hits: Vec<_> = oc.ray_intersections(Ray3::new(Point3::new(3.0, 3.0, 0.0),
+                                                              vec3(0.001, 0.001, 1.0).normalize()))
+                .collect();
 
-        assert_eq!(1, hits.len());
-        assert_eq!(hits[0].item().id, 1);
+            assert_eq!(1, hits.len());
+            assert_eq!(hits[0].item().id, 1);
+        }
     }
 
     #[test]
     fn should_be_able_to_remove_all() {
-        let mut oc = Octree::with_branch_size(1,
-                                              Aabb3::new(Point3::new(0.0, 0.0, 0.0),
-                                                         Point3::new(10.0, 10.0, 10.0)));
-        oc.update(1,
-                  Aabb3::new(Point3::new(2.0, 2.0, 2.0), Point3::new(5.0, 5.0, 5.0)));
-
-        oc.update(2,
-                  Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)));
-
-        assert!(oc.root.is_branch);
-
-        assert_eq!(oc.remove(3), None);
-        assert_eq!(oc.remove(2),
-                   Some(Item {
-                       id: 2,
-                       bb: Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)),
-                   }));
-
-        let list: Vec<_> = oc.into_iter().cloned().collect();
-        assert_eq!(1, list.len());
-        assert_eq!(list[0].id, 1);
-
-        assert_eq!(oc.remove(1),
-                   Some(Item {
-                       id: 1,
-                       bb: Aabb3::new(Point3::new(2.0, 2.0, 2.0), Point3::new(5.0, 5.0, 5.0)),
-                   }));
-
-        assert!(!oc.root.is_branch);
-        assert!(oc.root.leafs.is_empty());
+        for &mode in &MODES {
+            let mut oc = tree_with_mode(mode);
+            oc.update(1,
+                      Aabb3::new(Point3::new(2.0, 2.0, 2.0), Point3::new(5.0, 5.0, 5.0)));
+
+            oc.update(2,
+                      Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)));
+
+            assert!(oc.root.is_branch);
+
+            assert_eq!(oc.remove(3), None);
+            assert_eq!(oc.remove(2),
+                       Some(Item {
+                           id: 2,
+                           bb: Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)),
+                       }));
+
+            let list: Vec<_> = oc.into_iter().cloned().collect();
+            assert_eq!(1, list.len());
+            assert_eq!(list[0].id, 1);
+
+            assert_eq!(oc.remove(1),
+                       Some(Item {
+                           id: 1,
+                           bb: Aabb3::new(Point3::new(2.0, 2.0, 2.0), Point3::new(5.0, 5.0, 5.0)),
+                       }));
+
+            assert!(!oc.root.is_branch);
+            assert!(oc.root.leafs.is_empty());
+        }
     }
 
     #[test]
     fn items_moved_outside_of_the_world_should_be_moved_to_root_node() {
-        let mut oc = Octree::with_branch_size(1,
-                                              Aabb3::new(Point3::new(0.0, 0.0, 0.0),
-                                                         Point3::new(10.0, 10.0, 10.0)));
-        oc.update(1,
-                  Aabb3::new(Point3::new(2.0, 2.0, 2.0), Point3::new(5.0, 5.0, 5.0)));
+        for &mode in &MODES {
+            let mut oc = tree_with_mode(mode);
+            oc.update(1,
+                      Aabb3::new(Point3::new(2.0, 2.0, 2.0), Point3::new(5.0, 5.0, 5.0)));
 
-        oc.update(2,
-                  Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)));
+            oc.update(2,
+                      Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)));
 
-        oc.update(2,
-                  Aabb3::new(Point3::new(-2.0, -2.0, -2.0), Point3::new(-1.0, -1.0, -1.0)));
+            oc.update(2,
+                      Aabb3::new(Point3::new(-2.0, -2.0, -2.0), Point3::new(-1.0, -1.0, -1.0)));
 
-        let list: Vec<_> = oc.into_iter().cloned().collect();
+            let list: Vec<_> = oc.into_iter().cloned().collect();
 
-        assert_eq!(2, list.len());
-        assert!(!oc.root.is_branch);
-        assert_eq!(2, oc.root.leafs.len());
+            assert_eq!(2, list.len());
+            assert!(!oc.root.is_branch);
+            assert_eq!(2, oc.root.leafs.len());
 
-        oc.update(1,
-                  Aabb3::new(Point3::new(-2.0, -2.0, -2.0), Point3::new(-1.0, -1.0, -1.0)));
+            oc.update(1,
+                      Aabb3::new(Point3::new(-2.0, -2.0, -2.0), Point3::new(-1.0, -1.0, -1.0)));
 
-        let list: Vec<_> = oc.into_iter().cloned().collect();
+            let list: Vec<_> = oc.into_iter().cloned().collect();
 
-        assert_eq!(2, list.len());
-        assert!(!oc.root.is_branch);
-        assert_eq!(2, oc.root.leafs.len());
+            assert_eq!(2, list.len());
+            assert!(!oc.root.is_branch);
+            assert_eq!(2, oc.root.leafs.len());
 
-        assert_eq!(list[0].bb,
-                   Aabb3::new(Point3::new(-2.0, -2.0, -2.0), Point3::new(-1.0, -1.0, -1.0)));
-        assert_eq!(list[1].bb,
-                   Aabb3::new(Point3::new(-2.0, -2.0, -2.0), Point3::new(-1.0, -1.0, -1.0)));
+            assert_eq!(list[0].bb,
+                       Aabb3::new(Point3::new(-2.0, -2.0, -2.0), Point3::new(-1.0, -1.0, -1.0)));
+            assert_eq!(list[1].bb,
+                       Aabb3::new(Point3::new(-2.0, -2.0, -2.0), Point3::new(-1.0, -1.0, -1.0)));
+        }
     }
 
     #[test]
     fn items_moved_outside_the_world_should_not_create_subnodes() {
-        let mut oc = Octree::with_branch_size(1,
-                                              Aabb3::new(Point3::new(0.0, 0.0, 0.0),
-                                                         Point3::new(10.0, 10.0, 10.0)));
+        for &mode in &MODES {
+            let mut oc = tree_with_mode(mode);
 
-        oc.update(1,
-                  Aabb3::new(Point3::new(-2.0, -2.0, -2.0), Point3::new(-1.0, -1.0, -1.0)));
+            oc.update(1,
+                      Aabb3::new(Point3::new(-2.0, -2.0, -2.0), Point3::new(-1.0, -1.0, -1.0)));
 
-        oc.update(2,
-                  Aabb3::new(Point3::new(-2.0, -2.0, -2.0), Point3::new(-1.0, -1.0, -1.0)));
+            oc.update(2,
+                      Aabb3::new(Point3::new(-2.0, -2.0, -2.0), Point3::new(-1.0, -1.0, -1.0)));
 
-        oc.update(1,
-                  Aabb3::new(Point3::new(-3.0, -2.0, -2.0), Point3::new(-1.0, -1.0, -1.0)));
+            oc.update(1,
+                      Aabb3::new(Point3::new(-3.0, -2.0, -2.0), Point3::new(-1.0, -1.0, -1.0)));
 
-        let list: Vec<_> = oc.into_iter().cloned().collect();
+            let list: Vec<_> = oc.into_iter().cloned().collect();
 
-        assert_eq!(2, list.len());
-        assert!(!oc.root.is_branch);
+            assert_eq!(2, list.len());
+            assert!(!oc.root.is_branch);
+        }
     }
 
     #[test]
     fn items_moved_into_the_world_should_be_moved_to_subnodes() {
-        let mut oc = Octree::with_branch_size(1,
-                                              Aabb3::new(Point3::new(0.0, 0.0, 0.0),
-                                                         Point3::new(10.0, 10.0, 10.0)));
+        for &mode in &MODES {
+            let mut oc = tree_with_mode(mode);
 
-        oc.update(1,
-                  Aabb3::new(Point3::new(-2.0, -2.0, -2.0), Point3::new(-1.0, -1.0, -1.0)));
+            oc.update(1,
+                      Aabb3::new(Point3::new(-2.0, -2.0, -2.0), Point3::new(-1.0, -1.0, -1.0)));
 
-        oc.update(2,
-                  Aabb3::new(Point3::new(-2.0, -2.0, -2.0), Point3::new(-1.0, -1.0, -1.0)));
+            oc.update(2,
+                      Aabb3::new(Point3::new(-2.0, -2.0, -2.0), Point3::new(-1.0, -1.0, -1.0)));
 
-        let list: Vec<_> = oc.into_iter().cloned().collect();
+            let list: Vec<_> = oc.into_iter().cloned().collect();
 
-        assert_eq!(2, list.len());
-        assert!(!oc.root.is_branch);
+            assert_eq!(2, list.len());
+            assert!(!oc.root.is_branch);
 
-        oc.update(2,
-                  Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)));
+            oc.update(2,
+                      Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)));
+
+            assert_eq!(2, list.len());
+            assert!(oc.root.is_branch);
+
+            oc.update(1,
+                      Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)));
+
+            assert_eq!(2, list.len());
+            assert!(oc.root.is_branch);
+
+            oc.remove(1);
+            oc.remove(2);
+
+            let list: Vec<_> = oc.into_iter().cloned().collect();
+
+            assert_eq!(0, list.len());
+            assert!(!oc.root.is_branch);
+        }
+    }
+
+    #[test]
+    fn should_reject_invalid_tree_configuration() {
+        let world = Aabb3::new(Point3::new(0.0, 0.0, 0.0), Point3::new(10.0, 10.0, 10.0));
+        let flat = Aabb3::new(Point3::new(0.0, 0.0, 0.0), Point3::new(10.0, 10.0, 0.0));
+        let nan = Aabb3 {
+            min: Point3::new(0.0, 0.0, 0.0),
+            max: Point3::new(10.0, f64::NAN, 10.0),
+        };
+
+        assert_eq!(Octree::<f64, i32>::try_with_branch_size(0, world).err(),
+                   Some(Error::ZeroBranchSize));
+        assert_eq!(Octree::<f64, i32>::try_new(flat).err(), Some(Error::EmptyWorld));
+        assert_eq!(Octree::<f64, i32>::try_new(nan).err(), Some(Error::NonFiniteBounds));
+        assert!(Octree::<f64, i32>::try_new(world).is_ok());
+    }
+
+    #[test]
+    fn should_reject_invalid_item_volumes() {
+        let mut oc = Octree::with_branch_size(1,
+                                              Aabb3::new(Point3::new(0.0, 0.0, 0.0),
+                                                         Point3::new(10.0, 10.0, 10.0)));
+        oc.try_update(1,
+                        Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)))
+            .unwrap();
+
+        let inverted = Aabb3 {
+            min: Point3::new(5.0, 1.0, 1.0),
+            max: Point3::new(4.0, 2.0, 2.0),
+        };
+        assert_eq!(oc.try_update(1, inverted), Err(Error::InvertedBounds));
+        assert_eq!(oc.try_update(2,
+                                 Aabb3::new(Point3::new(1.0, 1.0, 1.0),
+                                            Point3::new(f64::INFINITY, 2.0, 2.0))),
+                   Err(Error::NonFiniteBounds));
+        let nan = Aabb3 {
+            min: Point3::new(f64::NAN, 1.0, 1.0),
+            max: Point3::new(2.0, 2.0, 2.0),
+        };
+        assert_eq!(oc.try_update(2, nan), Err(Error::NonFiniteBounds));
+
+        let list: Vec<_> = oc.into_iter().cloned().collect();
+        assert_eq!(1, list.len());
+        assert_eq!(list[0].bb,
+                   Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)));
+    }
+
+    #[test]
+    fn should_subdivide_integer_world_on_integer_midpoints() {
+        let mut oc = Octree::with_branch_size(1,
+                                              Aabb3::new(Point3::new(0i32, 0, 0),
+                                                         Point3::new(5, 5, 5)));
+        oc.update(1, Aabb3::new(Point3::new(0, 0, 0), Point3::new(2, 2, 2)));
+        oc.update(2, Aabb3::new(Point3::new(2, 2, 2), Point3::new(5, 5, 5)));
 
-        assert_eq!(2, list.len());
         assert!(oc.root.is_branch);
+        assert_eq!(oc.root.get_node(0).bb,
+                   Aabb3::new(Point3::new(0, 0, 0), Point3::new(2, 2, 2)));
+        assert_eq!(oc.root.get_node(7).bb,
+                   Aabb3::new(Point3::new(2, 2, 2), Point3::new(5, 5, 5)));
+        assert_eq!(oc.root.get_node(0).leafs[0].id, 1);
+        assert_eq!(oc.root.get_node(7).leafs[0].id, 2);
+
+        oc.update(2, Aabb3::new(Point3::new(1, 1, 1), Point3::new(3, 3, 3)));
+        assert_eq!(oc.root.leafs[0].id, 2);
+    }
+
+    #[test]
+    fn should_not_subdivide_integer_cells_narrower_than_two() {
+        let mut oc = Octree::with_branch_size(1,
+                                              Aabb3::new(Point3::new(0u32, 0, 0),
+                                                         Point3::new(1, 8, 8)));
+        oc.update(1, Aabb3::new(Point3::new(0, 0, 0), Point3::new(0, 0, 0)));
+        oc.update(2, Aabb3::new(Point3::new(0, 0, 0), Point3::new(0, 0, 0)));
+        oc.update(3, Aabb3::new(Point3::new(1, 1, 1), Point3::new(1, 1, 1)));
+
+        assert!(!oc.root.is_branch);
+        assert_eq!(3, oc.into_iter().count());
+
+        assert_eq!(oc.remove(2).map(|item| item.id), Some(2));
+        assert_eq!(2, oc.into_iter().count());
+    }
 
+    #[test]
+    fn should_handle_integer_worlds_spanning_most_of_the_range() {
+        let world = Aabb3::new(Point3::new(-2_000_000_000i32, -2_000_000_000, -2_000_000_000),
+                               Point3::new(2_000_000_000, 2_000_000_000, 2_000_000_000));
+        assert_eq!(Octree::<i32, u32>::try_new(world).err(),
+                   Some(Error::CoordinateOutOfRange));
+
+        // the sum of the bounds overflows, but the extent does not
+        let world = Aabb3::new(Point3::new(100_000_000i32, 100_000_000, 100_000_000),
+                               Point3::new(2_100_000_000, 2_100_000_000, 2_100_000_000));
+        let mut oc = Octree::<i32, u32>::try_with_branch_size(1, world).unwrap();
+        for id in 0..20 {
+            let p = Point3::new(2_000_000_000 - id as i32 * 90_000_000, 2_000_000_000, 150_000_000);
+            oc.update(id, Aabb3::new(p, p + vec3(10, 10, 10)));
+        }
+        assert!(oc.root_node().is_branch());
+        assert_eq!(oc.root_node().child(0).unwrap().bounds().max,
+                   Point3::new(1_100_000_000, 1_100_000_000, 1_100_000_000));
+        assert_eq!(oc.into_iter().count(), 20);
+    }
+
+    #[test]
+    fn should_reject_origin_shifts_that_overflow() {
+        let mut oc = Octree::with_branch_size(1,
+                                              Aabb3::new(Point3::new(0u32, 0, 0),
+                                                         Point3::new(100, 100, 100)));
+        oc.update(1, Aabb3::new(Point3::new(10, 10, 10), Point3::new(20, 20, 20)));
+        // an item outside of the world extends the range to check
+        oc.update(2, Aabb3::new(Point3::new(200, 10, 10), Point3::new(210, 20, 20)));
+
+        assert_eq!(oc.try_shift_origin(vec3(5, 0, 1)), Err(Error::CoordinateOutOfRange));
+        assert_eq!(oc.root_node().bounds(),
+                   Aabb3::new(Point3::new(0, 0, 0), Point3::new(100, 100, 100)));
+
+        let mut oc = Octree::with_branch_size(1,
+                                              Aabb3::new(Point3::new(10i8, 10, 10),
+                                                         Point3::new(100, 100, 100)));
+        oc.update(1, Aabb3::new(Point3::new(10, 10, 10), Point3::new(20, 20, 20)));
+        assert_eq!(oc.try_shift_origin(vec3(-50, 0, 0)), Err(Error::CoordinateOutOfRange));
+        assert_eq!(oc.try_shift_origin(vec3(10, 10, 10)), Ok(()));
+        assert_eq!(oc.root_node().bounds(),
+                   Aabb3::new(Point3::new(0, 0, 0), Point3::new(90, 90, 90)));
+        assert_eq!(oc.into_iter().next().unwrap().bb,
+                   Aabb3::new(Point3::new(0, 0, 0), Point3::new(10, 10, 10)));
+
+        // floating point coordinates must stay finite
+        let mut oc = Octree::with_branch_size(1,
+                                              Aabb3::new(Point3::new(0.0f64, 0.0, 0.0),
+                                                         Point3::new(1e308, 1e308, 1e308)));
+        oc.update(1, Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)));
+        assert_eq!(oc.try_shift_origin(vec3(-1.7e308, 0.0, 0.0)),
+                   Err(Error::CoordinateOutOfRange));
+        assert_eq!(oc.try_shift_origin(vec3(f64::NAN, 0.0, 0.0)),
+                   Err(Error::CoordinateOutOfRange));
+        assert_eq!(oc.root_node().bounds().max, Point3::new(1e308, 1e308, 1e308));
+        assert_eq!(oc.try_shift_origin(vec3(1.0, 1.0, 1.0)), Ok(()));
+        assert_eq!(oc.into_iter().next().unwrap().bb.min, Point3::new(0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn should_shift_origin_without_restructuring() {
+        let mut oc = Octree::with_branch_size(1,
+                                              Aabb3::new(Point3::new(100000.0f32,
+                                                                     100000.0,
+                                                                     100000.0),
+                                                         Point3::new(100010.0,
+                                                                     100010.0,
+                                                                     100010.0)));
         oc.update(1,
+                  Aabb3::new(Point3::new(100002.0, 100002.0, 100002.0),
+                             Point3::new(100005.0, 100005.0, 100005.0)));
+        oc.update(2,
+                  Aabb3::new(Point3::new(100001.0, 100001.0, 100001.0),
+                             Point3::new(100002.0, 100002.0, 100002.0)));
+
+        let depths_before: Vec<_> = oc.debug_items()
+            .filter_map(|i| match i {
+                DebugItem::Item { item, depth, .. } => Some((item.id, depth)),
+                _ => None,
+            })
+            .collect();
+
+        oc.shift_origin(vec3(100000.0, 100000.0, 100000.0));
+
+        assert_eq!(oc.root.bb,
+                   Aabb3::new(Point3::new(0.0, 0.0, 0.0), Point3::new(10.0, 10.0, 10.0)));
+
+        let depths_after: Vec<_> = oc.debug_items()
+            .filter_map(|i| match i {
+                DebugItem::Item { item, depth, .. } => Some((item.id, depth)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(depths_before, depths_after);
+
+        let mut list: Vec<_> = oc.into_iter().cloned().collect();
+        list.sort_by_key(|item| item.id);
+        assert_eq!(list[0].bb,
+                   Aabb3::new(Point3::new(2.0, 2.0, 2.0), Point3::new(5.0, 5.0, 5.0)));
+        assert_eq!(list[1].bb,
+                   Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)));
+
+        oc.update(2,
+                  Aabb3::new(Point3::new(6.0, 6.0, 6.0), Point3::new(7.0, 7.0, 7.0)));
+        assert_eq!(2, oc.into_iter().count());
+    }
+
+    struct RegionVisitor {
+        region: Aabb3<f64>,
+        nodes_entered: usize,
+        found: Vec<i32>,
+    }
+
+    impl Visitor<f64, i32> for RegionVisitor {
+        fn enter_node(&mut self, node: NodeRef<f64, i32>) -> Visit {
+            let bb = node.bounds();
+            if node.depth() > 0 &&
+               (bb.max.x < self.region.min.x || bb.min.x > self.region.max.x ||
+                bb.max.y < self.region.min.y || bb.min.y > self.region.max.y ||
+                bb.max.z < self.region.min.z || bb.min.z > self.region.max.z) {
+                return Visit::SkipNode;
+            }
+
+            self.nodes_entered += 1;
+            Visit::Continue
+        }
+
+        fn visit_item(&mut self, item: &Item<f64, i32>) -> Visit {
+            if item.bb.min.x <= self.region.max.x && item.bb.max.x >= self.region.min.x {
+                self.found.push(item.id);
+            }
+            if self.found.len() == 2 {
+                return Visit::Stop;
+            }
+            Visit::Continue
+        }
+    }
+
+    struct ItemsVisitor(Vec<i32>);
+
+    impl Visitor<f64, i32> for ItemsVisitor {
+        fn enter_node(&mut self, _: NodeRef<f64, i32>) -> Visit {
+            Visit::SkipChildren
+        }
+
+        fn visit_item(&mut self, item: &Item<f64, i32>) -> Visit {
+            self.0.push(item.id);
+            Visit::Continue
+        }
+    }
+
+    #[test]
+    fn should_walk_nodes_with_cursor_and_visitor() {
+        let mut oc = Octree::with_branch_size(1,
+                                              Aabb3::new(Point3::new(0.0, 0.0, 0.0),
+                                                         Point3::new(10.0, 10.0, 10.0)));
+        oc.update(1,
+                  Aabb3::new(Point3::new(2.0, 2.0, 2.0), Point3::new(5.0, 5.0, 5.0)));
+        oc.update(2,
                   Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)));
+        oc.update(3,
+                  Aabb3::new(Point3::new(7.0, 7.0, 7.0), Point3::new(8.0, 8.0, 8.0)));
+
+        let root = oc.root_node();
+        assert_eq!(root.depth(), 0);
+        assert_eq!(root.octant(), None);
+        assert!(root.parent().is_none());
+        assert_eq!(root.children().count(), 8);
+
+        assert_eq!(root.items()[0].id, 3);
+
+        let child = root.child(0).unwrap();
+        assert_eq!(child.depth(), 1);
+        assert_eq!(child.octant(), Some(0));
+        assert_eq!(child.bounds(),
+                   Aabb3::new(Point3::new(0.0, 0.0, 0.0), Point3::new(5.0, 5.0, 5.0)));
+        assert_eq!(child.items()[0].id, 1);
+        assert_eq!(child.parent().unwrap().bounds(), root.bounds());
+
+        let leaf = child.children().find(|n| !n.items().is_empty()).unwrap();
+        assert_eq!(leaf.depth(), 2);
+        assert_eq!(leaf.octant(), Some(0));
+        assert_eq!(leaf.items()[0].id, 2);
+        assert_eq!(leaf.children().count(), 0);
+        assert!(leaf.child(0).is_none());
+
+        let mut visitor = RegionVisitor {
+            region: Aabb3::new(Point3::new(0.0, 0.0, 0.0), Point3::new(5.0, 5.0, 5.0)),
+            nodes_entered: 0,
+            found: Vec::new(),
+        };
+        oc.visit(&mut visitor);
+
+        visitor.found.sort();
+        assert_eq!(visitor.found, vec![1, 2]);
+        assert!(visitor.nodes_entered < oc.debug_items().count());
+
+        // skipping children still visits the items of the node
+        let mut visitor = ItemsVisitor(Vec::new());
+        oc.visit(&mut visitor);
+        assert_eq!(visitor.0, vec![3]);
+    }
 
-        assert_eq!(2, list.len());
-        assert!(oc.root.is_branch);
+    #[test]
+    fn should_export_tree_structure() {
+        let mut oc = Octree::with_branch_size(1,
+                                              Aabb3::new(Point3::new(0.0, 0.0, 0.0),
+                                                         Point3::new(10.0, 10.0, 10.0)));
+        oc.update(1,
+                  Aabb3::new(Point3::new(2.0, 2.0, 2.0), Point3::new(5.0, 5.0, 5.0)));
+        oc.update(2,
+                  Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)));
+
+        // root, 8 children, 8 grandchildren and 2 items
+        let box_count = 19;
+
+        let mut obj = Vec::new();
+        export::write_obj(&oc, &mut obj).unwrap();
+        let obj = String::from_utf8(obj).unwrap();
+        assert_eq!(box_count, obj.lines().filter(|l| l.starts_with("o ")).count());
+        assert_eq!(box_count * 8, obj.lines().filter(|l| l.starts_with("v ")).count());
+        assert_eq!(box_count * 6, obj.lines().filter(|l| l.starts_with("f ")).count());
+        assert!(obj.contains("o item_1\n"));
+        assert!(obj.contains("o node_0\n# depth 0 goodness 0\n"));
+        assert!(obj.contains("o node_1\n# depth 1 goodness 0.11111111\n"));
+        assert!(obj.ends_with(&format!("f {} {} {} {}\n", box_count * 8 - 6, box_count * 8 - 4,
+                                       box_count * 8, box_count * 8 - 2)));
+
+        let mut ply = Vec::new();
+        export::write_ply(&oc, &mut ply).unwrap();
+        let ply = String::from_utf8(ply).unwrap();
+        assert!(ply.contains(&format!("element vertex {}\n", box_count * 8)));
+        assert!(ply.contains(&format!("element face {}\n", box_count * 6)));
+        let body: Vec<_> = ply.lines().skip_while(|l| *l != "end_header").skip(1).collect();
+        assert_eq!(box_count * 14, body.len());
+        assert_eq!("0 0 0 255 0 0 0 0", body[0]);
+
+        let mut dot = Vec::new();
+        export::write_dot(&oc, &mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.starts_with("digraph octree {\n"));
+        assert_eq!(17, dot.lines().filter(|l| l.contains("[label=\"depth")).count());
+        assert_eq!(16, dot.lines().filter(|l| l.contains(" -> ")).count());
+        assert!(dot.contains("n0 [label=\"depth 0\\n0 items\"];"));
+        assert!(dot.contains("n1 [label=\"depth 1\\n1 items\"];"));
+        assert!(dot.contains("n2 [label=\"depth 2\\n1 items\"];"));
+    }
+
+    #[test]
+    fn should_record_structural_events() {
+        let mut oc = Octree::with_branch_size(1,
+                                              Aabb3::new(Point3::new(0.0, 0.0, 0.0),
+                                                         Point3::new(10.0, 10.0, 10.0)));
+        oc.update(1,
+                  Aabb3::new(Point3::new(2.0, 2.0, 2.0), Point3::new(3.0, 3.0, 3.0)));
+        assert!(oc.take_events().is_empty());
+
+        oc.record_events(true);
+        let root = oc.root_node().id();
+
+        oc.update(2,
+                  Aabb3::new(Point3::new(7.0, 7.0, 7.0), Point3::new(8.0, 8.0, 8.0)));
+        let events = oc.take_events();
+        let children = match events[0] {
+            Event::Subdivided { node, children } => {
+                assert_eq!(node, root);
+                children
+            }
+            ref other => panic!("unexpected event {:?}", other),
+        };
+        assert_eq!(children, {
+            let mut ids = [NodeId(0); 8];
+            for (i, child) in oc.root_node().children().enumerate() {
+                ids[i] = child.id();
+            }
+            ids
+        });
+        assert_eq!(&events[1..],
+                   &[Event::ItemMoved {
+                         id: 1,
+                         from: root,
+                         to: children[0],
+                     },
+                     Event::ItemInserted {
+                         id: 2,
+                         node: children[7],
+                     }]);
+
+        oc.update(2,
+                  Aabb3::new(Point3::new(4.0, 4.0, 4.0), Point3::new(6.0, 6.0, 6.0)));
+        assert_eq!(oc.take_events(),
+                   vec![Event::ItemMoved {
+                            id: 2,
+                            from: children[7],
+                            to: root,
+                        }]);
 
         oc.remove(1);
+        assert_eq!(oc.take_events(),
+                   vec![Event::ItemRemoved {
+                            id: 1,
+                            node: children[0],
+                        },
+                        Event::Collapsed {
+                            node: root,
+                            children: children,
+                        }]);
+        assert!(!oc.root.is_branch);
+
+        oc.record_events(false);
         oc.remove(2);
+        assert!(oc.take_events().is_empty());
+    }
 
-        let list: Vec<_> = oc.into_iter().cloned().collect();
+    #[test]
+    fn should_not_free_node_when_moving_its_only_item_to_child() {
+        let mut oc = Octree::with_branch_size(1,
+                                              Aabb3::new(Point3::new(0.0, 0.0, 0.0),
+                                                         Point3::new(10.0, 10.0, 10.0)));
+        oc.update(1,
+                  Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(4.0, 4.0, 4.0)));
+        oc.update(2,
+                  Aabb3::new(Point3::new(7.0, 7.0, 7.0), Point3::new(8.0, 8.0, 8.0)));
+        oc.remove(2);
+        oc.update(3,
+                  Aabb3::new(Point3::new(7.0, 7.0, 7.0), Point3::new(8.0, 8.0, 8.0)));
+
+        // item 1 is alone in a child whose siblings are empty
+        oc.update(1,
+                  Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)));
 
-        assert_eq!(0, list.len());
+        let mut ids: Vec<_> = oc.into_iter().map(|item| item.id).collect();
+        ids.sort();
+        assert_eq!(ids, vec![1, 3]);
+        assert_eq!(oc.remove(1).map(|item| item.id), Some(1));
+        assert_eq!(oc.remove(3).map(|item| item.id), Some(3));
         assert!(!oc.root.is_branch);
     }
+
+    const MODES: [StraddleMode; 2] = [StraddleMode::Parent, StraddleMode::Children];
+
+    fn tree_with_mode(mode: StraddleMode) -> Octree<f64, i32> {
+        let mut oc = Octree::with_branch_size(1,
+                                              Aabb3::new(Point3::new(0.0, 0.0, 0.0),
+                                                         Point3::new(10.0, 10.0, 10.0)));
+        oc.set_straddle_mode(mode);
+        oc
+    }
+
+    fn sorted_ids(oc: &Octree<f64, i32>) -> Vec<i32> {
+        let mut ids: Vec<_> = oc.into_iter().map(|item| item.id).collect();
+        ids.sort();
+        ids
+    }
+
+    /// Simple deterministic pseudo-random generator for tests.
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self, max: f64) -> f64 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (self.0 >> 11) as f64 / (1u64 << 53) as f64 * max
+        }
+
+        /// Point in a volume slightly larger than the test world.
+        fn next_point(&mut self) -> Point3<f64> {
+            Point3::new(self.next(12.0) - 1.0, self.next(12.0) - 1.0, self.next(12.0) - 1.0)
+        }
+
+        fn next_bb(&mut self) -> Aabb3<f64> {
+            let p = self.next_point();
+            Aabb3::new(p, p + vec3(self.next(3.0), self.next(3.0), self.next(3.0)))
+        }
+    }
+
+    #[test]
+    fn should_insert_query_and_remove_in_all_modes() {
+        for &mode in &MODES {
+            let mut oc = tree_with_mode(mode);
+            assert_eq!(oc.straddle_mode(), mode);
+
+            oc.update(1,
+                      Aabb3::new(Point3::new(2.0, 2.0, 2.0), Point3::new(5.0, 5.0, 5.0)));
+            oc.update(2,
+                      Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)));
+            oc.update(3,
+                      Aabb3::new(Point3::new(0.0, 4.0, 4.0), Point3::new(10.0, 4.5, 4.5)));
+            oc.update(4,
+                      Aabb3::new(Point3::new(-2.0, -2.0, -2.0), Point3::new(-1.0, -1.0, -1.0)));
+            assert_eq!(sorted_ids(&oc), vec![1, 2, 3, 4]);
+
+            let mut hits: Vec<_> = oc.ray_intersections(Ray3::new(Point3::new(0.0, 0.0, 0.0),
+                                                                  vec3(1.0, 1.0, 1.0)
+                                                                      .normalize()))
+                .map(|hit| hit.item().id)
+                .collect();
+            hits.sort();
+            assert_eq!(hits, vec![1, 2, 3]);
+
+            oc.update(2,
+                      Aabb3::new(Point3::new(-2.0, -2.0, -2.0), Point3::new(-1.0, -1.0, -1.0)));
+            oc.update(4,
+                      Aabb3::new(Point3::new(6.0, 6.0, 6.0), Point3::new(7.0, 7.0, 7.0)));
+            assert_eq!(sorted_ids(&oc), vec![1, 2, 3, 4]);
+
+            for id in 1..5 {
+                assert_eq!(oc.remove(id).map(|item| item.id), Some(id));
+                assert_eq!(oc.remove(id), None);
+            }
+
+            assert_eq!(0, oc.into_iter().count());
+            assert!(!oc.root.is_branch);
+            assert!(oc.root.leafs.is_empty());
+        }
+    }
+
+    #[test]
+    fn should_match_brute_force_in_all_modes() {
+        for &mode in &MODES {
+            let mut oc = tree_with_mode(mode);
+            let mut reference = HashMap::new();
+            let mut rng = Lcg(7);
+
+            for step in 0..2000 {
+                let id = rng.next(60.0) as i32;
+                if step % 5 == 4 {
+                    assert_eq!(oc.remove(id).map(|item| item.bb), reference.remove(&id));
+                } else {
+                    let bb = rng.next_bb();
+                    oc.update(id, bb);
+                    reference.insert(id, bb);
+                }
+
+                if step % 100 == 0 {
+                    let mut items: Vec<_> =
+                        oc.into_iter().map(|item| (item.id, item.bb)).collect();
+                    items.sort_by_key(|&(id, _)| id);
+                    let mut expected: Vec<_> =
+                        reference.iter().map(|(&id, &bb)| (id, bb)).collect();
+                    expected.sort_by_key(|&(id, _)| id);
+                    assert!(items == expected, "mode {:?} step {}", mode, step);
+
+                    let ray = Ray3::new(Point3::new(-1.0, 0.5, 0.3),
+                                        vec3(1.0, 0.8, 0.9).normalize());
+                    let mut hits: Vec<_> =
+                        oc.ray_intersections(ray).map(|hit| hit.item().id).collect();
+                    hits.sort();
+                    let mut expected: Vec<_> = reference.iter()
+                        .filter(|&(_, bb)| (ray, *bb).intersection().is_some())
+                        .map(|(&id, _)| id)
+                        .collect();
+                    expected.sort();
+                    assert_eq!(hits, expected);
+                }
+            }
+
+            for (id, _) in reference.drain() {
+                assert!(oc.remove(id).is_some());
+            }
+            assert!(!oc.root.is_branch);
+            assert!(oc.ctx.object_node.is_empty());
+        }
+    }
+
+    #[test]
+    fn should_reference_straddling_items_from_overlapping_children() {
+        let mut oc = tree_with_mode(StraddleMode::Children);
+        oc.update(1,
+                  Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)));
+        oc.update(2,
+                  Aabb3::new(Point3::new(0.5, 1.5, 1.5), Point3::new(9.5, 1.6, 1.6)));
+
+        assert!(oc.root.leafs.is_empty());
+        assert_eq!(oc.root.get_node(1).leafs[0].id, 2);
+        let references = oc.debug_items()
+            .filter(|i| match *i {
+                DebugItem::Item { item, .. } => item.id == 2,
+                _ => false,
+            })
+            .count();
+        assert!(references > 1);
+        assert_eq!(sorted_ids(&oc), vec![1, 2]);
+
+        oc.set_straddle_mode(StraddleMode::Parent);
+        assert!(oc.root.leafs.iter().any(|item| item.id == 2));
+        assert_eq!(sorted_ids(&oc), vec![1, 2]);
+    }
+
+    #[test]
+    fn should_find_closest_item_in_all_modes() {
+        for &mode in &MODES {
+            let mut oc = tree_with_mode(mode);
+            assert!(oc.closest_item(Point3::new(1.0, 1.0, 1.0)).is_none());
+
+            oc.update(1,
+                      Aabb3::new(Point3::new(2.0, 2.0, 2.0), Point3::new(5.0, 5.0, 5.0)));
+            oc.update(2,
+                      Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)));
+            oc.update(3,
+                      Aabb3::new(Point3::new(8.0, 8.0, 1.0), Point3::new(9.0, 9.0, 9.0)));
+            oc.update(4,
+                      Aabb3::new(Point3::new(-3.0, -3.0, -3.0), Point3::new(-2.0, -2.0, -2.0)));
+
+            let closest = oc.closest_item(Point3::new(7.0, 8.5, 5.0)).unwrap();
+            assert_eq!(closest.item().id, 3);
+            assert_eq!(closest.point(), Point3::new(8.0, 8.5, 5.0));
+            assert_eq!(closest.distance(), 1.0);
+
+            let closest = oc.closest_item(Point3::new(3.0, 3.0, 3.0)).unwrap();
+            assert_eq!(closest.item().id, 1);
+            assert_eq!(closest.distance(), 0.0);
+
+            let closest = oc.closest_item(Point3::new(-4.0, -3.0, -3.0)).unwrap();
+            assert_eq!(closest.item().id, 4);
+            assert_eq!(closest.distance(), 1.0);
+
+            assert_eq!(oc.distance_to_nearest(Point3::new(1.5, 1.5, -1.0), 3.0), Some(2.0));
+            assert_eq!(oc.distance_to_nearest(Point3::new(1.5, 1.5, -1.0), 1.0), None);
+            assert_eq!(oc.distance_to_nearest(Point3::new(1.5, 1.5, -1.0), -3.0), None);
+            assert_eq!(oc.distance_to_nearest(Point3::new(1.5, 1.5, -1.0), f64::NAN), None);
+        }
+    }
+
+    #[test]
+    fn closest_item_should_match_brute_force() {
+        let mut oc = tree_with_mode(StraddleMode::Parent);
+        let mut rng = Lcg(11);
+        let mut reference = Vec::new();
+        for id in 0..200 {
+            let bb = rng.next_bb();
+            oc.update(id, bb);
+            reference.push(bb);
+        }
+
+        for _ in 0..50 {
+            let point = rng.next_point();
+            let expected = reference.iter()
+                .map(|bb| query::distance2(bb, point))
+                .fold(f64::INFINITY, f64::min)
+                .sqrt();
+
+            assert_eq!(oc.closest_item(point).unwrap().distance(), expected);
+            assert_eq!(oc.distance_to_nearest(point, expected + 0.01), Some(expected));
+            if expected > 0.0 {
+                assert_eq!(oc.distance_to_nearest(point, expected * 0.99), None);
+            }
+        }
+    }
+
+    #[test]
+    fn should_test_shape_overlap_exactly() {
+        let bb = |min: (f64, f64, f64), max: (f64, f64, f64)| {
+            Aabb3::new(Point3::new(min.0, min.1, min.2), Point3::new(max.0, max.1, max.2))
+        };
+
+        let sphere = Sphere {
+            center: Point3::new(0.0, 0.0, 0.0),
+            radius: 1.0,
+        };
+        assert!(sphere.overlaps_aabb(&bb((0.5, 0.5, 0.5), (2.0, 2.0, 2.0))));
+        assert!(!sphere.overlaps_aabb(&bb((0.6, 0.6, 0.6), (2.0, 2.0, 2.0))));
+
+        // diamond shaped in xy plane, reaching 1.414 along x and y axes
+        let obb = OrientedBox::new(Point3::new(0.0, 0.0, 0.0),
+                                   vec3(1.0, 1.0, 1.0),
+                                   Quaternion::from_angle_z(rad(::std::f64::consts::FRAC_PI_4)));
+        assert!(obb.overlaps_aabb(&bb((1.2, -0.1, -1.0), (2.0, 0.1, 1.0))));
+        assert!(obb.may_overlap_aabb(&bb((0.8, 0.8, -1.0), (2.0, 2.0, 1.0))));
+        assert!(!obb.overlaps_aabb(&bb((0.8, 0.8, -1.0), (2.0, 2.0, 1.0))));
+        assert!(!obb.overlaps_aabb(&bb((0.0, 0.0, 1.1), (2.0, 2.0, 2.0))));
+
+        let capsule = Capsule::new(Point3::new(0.0, 0.0, 0.0), Point3::new(10.0, 0.0, 0.0), 0.5);
+        assert!(capsule.overlaps_aabb(&bb((4.0, -1.0, -1.0), (5.0, 1.0, 1.0))));
+        assert!(capsule.overlaps_aabb(&bb((2.0, 0.3, 0.3), (3.0, 1.0, 1.0))));
+        assert!(!capsule.overlaps_aabb(&bb((2.0, 0.4, 0.4), (3.0, 1.0, 1.0))));
+        assert!(capsule.overlaps_aabb(&bb((-0.4, -0.4, 0.0), (-0.1, 0.0, 0.3))));
+
+        // closest points are on the segment middle and a box edge
+        let passing = Capsule::new(Point3::new(2.0, -3.0, 1.3), Point3::new(2.0, 3.0, 1.3), 0.5);
+        assert!(passing.overlaps_aabb(&bb((0.0, -1.0, 0.0), (1.8, 1.0, 1.0))));
+        let passing = Capsule { radius: 0.3, ..passing };
+        assert!(!passing.overlaps_aabb(&bb((0.0, -1.0, 0.0), (1.8, 1.0, 1.0))));
+    }
+
+    #[test]
+    fn overlapping_should_match_brute_force_in_all_modes() {
+        fn check<V>(oc: &Octree<f64, i32>, reference: &[Aabb3<f64>], volume: V)
+            where V: Overlap<f64>
+        {
+            let mut expected: Vec<_> = (0..reference.len() as i32)
+                .filter(|&id| volume.overlaps_aabb(&reference[id as usize]))
+                .collect();
+            let mut found: Vec<_> = oc.overlapping(&volume).map(|item| item.id).collect();
+            expected.sort();
+            found.sort();
+            assert_eq!(found, expected);
+        }
+
+        for &mode in &MODES {
+            let mut oc = tree_with_mode(mode);
+            let mut rng = Lcg(17);
+            let mut reference = Vec::new();
+            for id in 0..200 {
+                let bb = rng.next_bb();
+                oc.update(id, bb);
+                reference.push(bb);
+            }
+
+            for _ in 0..20 {
+                check(&oc, &reference, rng.next_bb());
+                check(&oc,
+                      &reference,
+                      Sphere {
+                          center: rng.next_point(),
+                          radius: rng.next(2.0),
+                      });
+                check(&oc,
+                      &reference,
+                      Capsule::new(rng.next_point(), rng.next_point(), rng.next(1.0)));
+                let rotation = Quaternion::from_angle_x(rad(rng.next(3.0))) *
+                               Quaternion::from_angle_y(rad(rng.next(3.0)));
+                check(&oc,
+                      &reference,
+                      OrientedBox::new(rng.next_point(),
+                                       vec3(rng.next(2.0), rng.next(2.0), rng.next(2.0)),
+                                       rotation));
+            }
+        }
+    }
+
+    #[test]
+    fn ray_packet_should_find_nearest_hit_of_each_ray() {
+        for &mode in &MODES {
+            let mut oc = tree_with_mode(mode);
+            oc.update(1,
+                      Aabb3::new(Point3::new(2.0, 0.0, 0.0), Point3::new(3.0, 1.0, 1.0)));
+            oc.update(2,
+                      Aabb3::new(Point3::new(6.0, 0.0, 0.0), Point3::new(7.0, 9.0, 1.0)));
+            oc.update(3,
+                      Aabb3::new(Point3::new(-0.5, 4.0, 0.0), Point3::new(0.5, 5.0, 1.0)));
+
+            let origin = Point3::new(0.0, 0.5, 0.5);
+            let rays = [Ray3::new(origin, vec3(1.0, 0.0, 0.0)),
+                        Ray3::new(origin, vec3(1.0, 1.0, 0.0).normalize()),
+                        Ray3::new(origin, vec3(0.0, 1.0, 0.0)),
+                        Ray3::new(origin, vec3(-1.0, 0.0, 0.0))];
+            let hits = oc.ray_packet(&rays);
+
+            assert_eq!(hits.len(), 4);
+            assert_eq!(hits[0].as_ref().map(|hit| (hit.item().id, hit.point())),
+                       Some((1, Point3::new(2.0, 0.5, 0.5))));
+            assert_eq!(hits[1].as_ref().map(|hit| hit.item().id), Some(2));
+            assert_eq!(hits[2].as_ref().map(|hit| (hit.item().id, hit.point())),
+                       Some((3, Point3::new(0.0, 4.0, 0.5))));
+            assert!(hits[3].is_none());
+
+            let inside = oc.ray_packet(&[Ray3::new(Point3::new(2.5, 0.5, 0.5),
+                                                   vec3(1.0, 0.0, 0.0))]);
+            assert_eq!(inside[0].as_ref().map(|hit| (hit.item().id, hit.point())),
+                       Some((1, Point3::new(2.5, 0.5, 0.5))));
+        }
+    }
+
+    #[test]
+    fn ray_packet_should_match_brute_force() {
+        for &mode in &MODES {
+            let mut oc = tree_with_mode(mode);
+            let mut rng = Lcg(23);
+            let mut reference = Vec::new();
+            for id in 0..200 {
+                let bb = rng.next_bb();
+                oc.update(id, bb);
+                reference.push(bb);
+            }
+
+            let origin = rng.next_point();
+            let rays: Vec<_> = (0..64)
+                .map(|_| {
+                    let target = rng.next_point();
+                    Ray3::new(origin, (target - origin).normalize())
+                })
+                .collect();
+
+            for (ray, hit) in rays.iter().zip(oc.ray_packet(&rays)) {
+                let expected = reference.iter()
+                    .filter_map(|bb| query::ray_entry(ray, bb))
+                    .fold(None, |acc: Option<f64>, t| Some(acc.map_or(t, |acc| acc.min(t))));
+                let found = hit.map(|hit| (hit.point() - ray.origin).magnitude());
+                match (found, expected) {
+                    (Some(found), Some(expected)) => assert!((found - expected).abs() < 1e-9),
+                    (found, expected) => assert_eq!(found, expected),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn should_keep_deterministic_order_after_removals() {
+        let build = |mode, removed: &[i32]| {
+            let mut oc = tree_with_mode(mode);
+            let mut rng = Lcg(29);
+            for id in 0..100 {
+                oc.update(id, rng.next_bb());
+            }
+            for &id in removed {
+                oc.remove(id);
+            }
+            oc
+        };
+
+        for &mode in &MODES {
+            let mut rng = Lcg(31);
+            let removed: Vec<i32> = (0..40).map(|_| rng.next(100.0) as i32).collect();
+            let mut reversed = removed.clone();
+            reversed.reverse();
+
+            let first = build(mode, &removed);
+            let ids: Vec<_> = first.into_iter().map(|item| item.id).collect();
+
+            // order does not depend on the order of removals
+            let second = build(mode, &reversed);
+            let second_ids: Vec<_> = second.into_iter().map(|item| item.id).collect();
+            assert_eq!(ids, second_ids);
+
+            let ray = Ray3::new(Point3::new(-1.0, -1.0, -1.0), vec3(1.0, 1.0, 1.0).normalize());
+            let hits = |oc: &Octree<f64, i32>| -> Vec<i32> {
+                oc.ray_intersections(ray).map(|hit| hit.item().id).collect()
+            };
+            assert_eq!(hits(&first), hits(&second));
+
+            let region = Aabb3::new(Point3::new(2.0, 2.0, 2.0), Point3::new(6.0, 6.0, 6.0));
+            let overlapping = |oc: &Octree<f64, i32>| -> Vec<i32> {
+                oc.overlapping(region).map(|item| item.id).collect()
+            };
+            assert_eq!(overlapping(&first), overlapping(&second));
+        }
+    }
+
+    #[test]
+    fn should_keep_insertion_order_of_items_in_node() {
+        let mut oc = Octree::with_branch_size(8,
+                                              Aabb3::new(Point3::new(0.0, 0.0, 0.0),
+                                                         Point3::new(10.0, 10.0, 10.0)));
+        for id in 0..6 {
+            let p = Point3::new(id as f64, 1.0, 1.0);
+            oc.update(id, Aabb3::new(p, p + vec3(0.5, 0.5, 0.5)));
+        }
+        oc.remove(1);
+        oc.remove(3);
+        oc.update(6, Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(1.5, 1.5, 1.5)));
+        oc.update(0, Aabb3::new(Point3::new(0.0, 2.0, 1.0), Point3::new(0.5, 2.5, 1.5)));
+
+        let ids: Vec<_> = oc.into_iter().map(|item| item.id).collect();
+        assert_eq!(ids, vec![0, 2, 4, 5, 6]);
+    }
+
+    #[test]
+    fn should_reuse_pooled_nodes() {
+        let count_nodes = |oc: &Octree<f64, i32>| {
+            fn count(node: NodeRef<f64, i32>) -> usize {
+                1 + node.children().map(count).sum::<usize>()
+            }
+            count(oc.root_node())
+        };
+
+        let mut oc = tree_with_mode(StraddleMode::Parent);
+        assert_eq!((oc.node_count(), oc.pooled_node_count()), (1, 0));
+
+        oc.reserve_nodes(16);
+        assert_eq!((oc.node_count(), oc.pooled_node_count()), (1, 16));
+        oc.reserve_nodes(8);
+        assert_eq!(oc.pooled_node_count(), 16);
+
+        let a = Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0));
+        let b = Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(1.5, 1.5, 1.5));
+        oc.update(1, a);
+        oc.update(2, b);
+        let subdivided = oc.node_count();
+        assert!(subdivided > 1);
+        assert_eq!(subdivided, count_nodes(&oc));
+
+        // nodes created and freed while moving items are recycled through the pool
+        let total = oc.node_count() + oc.pooled_node_count();
+        for _ in 0..10 {
+            oc.remove(2);
+            assert_eq!(oc.node_count(), count_nodes(&oc));
+            oc.update(2, b);
+            assert_eq!(oc.node_count(), subdivided);
+            assert_eq!(oc.node_count() + oc.pooled_node_count(), total);
+        }
+
+        oc.remove(1);
+        oc.remove(2);
+        assert_eq!(oc.node_count(), 1);
+        assert_eq!(oc.pooled_node_count(), total - 1);
+
+        oc.shrink_to_fit();
+        assert_eq!(oc.pooled_node_count(), 0);
+        oc.update(1, a);
+        oc.update(2, b);
+        assert_eq!(oc.node_count(), subdivided);
+        assert_eq!(sorted_ids(&oc), vec![1, 2]);
+    }
+
+    #[test]
+    fn rebalance_should_collapse_tree_after_bulk_removal() {
+        for &mode in &MODES {
+            let mut oc = Octree::with_branch_size(4,
+                                                  Aabb3::new(Point3::new(0.0, 0.0, 0.0),
+                                                             Point3::new(10.0, 10.0, 10.0)));
+            oc.set_straddle_mode(mode);
+            let mut rng = Lcg(37);
+            let mut reference = HashMap::new();
+            for id in 0..300 {
+                let p = rng.next_point();
+                let bb = Aabb3::new(p, p + vec3(0.2, 0.2, 0.2));
+                oc.update(id, bb);
+                reference.insert(id, bb);
+            }
+            assert!(!oc.rebalance_step(1));
+
+            for id in 0..300 {
+                if id % 50 != 0 {
+                    oc.remove(id);
+                    reference.remove(&id);
+                }
+            }
+            let before = oc.node_count();
+
+            let mut steps = 0;
+            while !oc.rebalance_step(5) {
+                steps += 1;
+                assert!(steps < 1000, "rebalancing did not converge");
+            }
+            assert!(oc.node_count() < before);
+            assert!(oc.rebalance_step(oc.node_count()));
+
+            // 6 remaining items fit into the root and one level of children at most
+            assert!(oc.node_count() <= 9);
+            let mut expected: Vec<_> = reference.keys().cloned().collect();
+            expected.sort();
+            assert_eq!(sorted_ids(&oc), expected);
+
+            let region = Aabb3::new(Point3::new(0.0, 0.0, 0.0), Point3::new(5.0, 5.0, 5.0));
+            let mut found: Vec<_> = oc.overlapping(region).map(|item| item.id).collect();
+            found.sort();
+            let mut expected: Vec<_> = reference.iter()
+                .filter(|&(_, bb)| region.overlaps_aabb(bb))
+                .map(|(&id, _)| id)
+                .collect();
+            expected.sort();
+            assert_eq!(found, expected);
+
+            // item index points to live nodes
+            for (&id, bb) in &reference {
+                oc.update(id, Aabb3::new(bb.min + vec3(0.1, 0.1, 0.1), bb.max));
+            }
+            for &id in reference.keys() {
+                assert!(oc.remove(id).is_some());
+            }
+            assert_eq!(oc.node_count(), 1);
+        }
+    }
+
+    #[test]
+    fn should_find_neighbor_leafs_across_faces() {
+        fn leafs<'a>(node: NodeRef<'a, f64, i32>, out: &mut Vec<NodeRef<'a, f64, i32>>) {
+            if !node.is_branch() {
+                out.push(node);
+            }
+            for child in node.children() {
+                leafs(child, out);
+            }
+        }
+
+        let mut oc = tree_with_mode(StraddleMode::Parent);
+        let mut rng = Lcg(41);
+        for id in 0..60 {
+            // denser near the origin, so that neighbours differ in depth
+            let p = Point3::new(rng.next(6.0), rng.next(6.0), rng.next(10.0));
+            oc.update(id, Aabb3::new(p, p + vec3(0.1, 0.1, 0.1)));
+        }
+
+        let mut all = Vec::new();
+        leafs(oc.root_node(), &mut all);
+        assert!(all.iter().any(|leaf| leaf.depth() != all[0].depth()));
+
+        for leaf in &all {
+            for &face in &Face::all() {
+                let bb = leaf.bounds();
+                let (axis, positive) = match face {
+                    Face::MinX => (0, false),
+                    Face::MaxX => (0, true),
+                    Face::MinY => (1, false),
+                    Face::MaxY => (1, true),
+                    Face::MinZ => (2, false),
+                    Face::MaxZ => (2, true),
+                };
+
+                let mut expected: Vec<_> = all.iter()
+                    .filter(|other| {
+                        let o = other.bounds();
+                        let touches = if positive {
+                            o.min[axis] == bb.max[axis]
+                        } else {
+                            o.max[axis] == bb.min[axis]
+                        };
+                        touches &&
+                        (0..3).filter(|&a| a != axis).all(|a| {
+                            o.min[a] < bb.max[a] && o.max[a] > bb.min[a]
+                        })
+                    })
+                    .map(|other| other.id())
+                    .collect();
+                let mut found: Vec<_> = leaf.neighbors(face).iter().map(|n| n.id()).collect();
+                expected.sort();
+                found.sort();
+                assert_eq!(found, expected);
+            }
+        }
+
+        let leaf = oc.leaf_at(Point3::new(0.1, 0.1, 0.1)).unwrap();
+        assert!(!leaf.is_branch());
+        assert!(leaf.depth() > 1);
+        assert_eq!(leaf.bounds().min, Point3::new(0.0, 0.0, 0.0));
+        assert!(leaf.neighbors(Face::MinX).is_empty());
+        assert!(oc.leaf_at(Point3::new(11.0, 1.0, 1.0)).is_none());
+    }
+
+    #[test]
+    fn should_merge_and_split_off_trees() {
+        let world = Aabb3::new(Point3::new(0.0, 0.0, 0.0), Point3::new(8.0, 8.0, 8.0));
+        let chunk = Aabb3::new(Point3::new(4.0, 0.0, 0.0), Point3::new(8.0, 4.0, 4.0));
+
+        for &mode in &MODES {
+            let mut oc = Octree::with_branch_size(2, world);
+            oc.set_straddle_mode(mode);
+            oc.update(1, Aabb3::new(Point3::new(0.5, 0.5, 0.5), Point3::new(1.0, 1.0, 1.0)));
+            oc.update(2, Aabb3::new(Point3::new(1.5, 0.5, 0.5), Point3::new(2.0, 1.0, 1.0)));
+            oc.update(3, Aabb3::new(Point3::new(0.5, 6.5, 0.5), Point3::new(1.0, 7.0, 1.0)));
+
+            let mut streamed = Octree::with_branch_size(2, chunk);
+            streamed.set_straddle_mode(mode);
+            let mut rng = Lcg(43);
+            let mut expected = HashMap::new();
+            for id in 10..40 {
+                let p = Point3::new(4.0 + rng.next(3.3), rng.next(3.3), rng.next(3.3));
+                let bb = Aabb3::new(p, p + vec3(0.5, 0.5, 0.5));
+                streamed.update(id, bb);
+                expected.insert(id, bb);
+            }
+            // outside of the chunk, but inside the world
+            let bb = Aabb3::new(Point3::new(3.0, 1.0, 1.0), Point3::new(5.0, 2.0, 2.0));
+            streamed.update(40, bb);
+            expected.insert(40, bb);
+
+            let mut conflicting = Octree::new(chunk);
+            conflicting.update(2,
+                               Aabb3::new(Point3::new(5.0, 1.0, 1.0),
+                                          Point3::new(6.0, 2.0, 2.0)));
+            assert_eq!(oc.merge(conflicting).err(), Some(Error::DuplicateKey));
+            assert_eq!(sorted_ids(&oc), vec![1, 2, 3]);
+
+            assert!(oc.merge(streamed).is_ok());
+            let mut ids = vec![1, 2, 3];
+            ids.extend(10..41);
+            assert_eq!(sorted_ids(&oc), ids);
+            assert_eq!(oc.node_count(),
+                       1 + oc.root_node().children().map(|c| {
+                           fn count(node: NodeRef<f64, i32>) -> usize {
+                               1 + node.children().map(count).sum::<usize>()
+                           }
+                           count(c)
+                       }).sum::<usize>());
+
+            // every item is indexed and can be moved around
+            for (&id, bb) in &expected {
+                assert_eq!(oc.overlapping(*bb).filter(|item| item.id == id).count(), 1);
+                oc.update(id, Aabb3::new(bb.min, bb.max + vec3(0.1, 0.1, 0.1)));
+            }
+
+            let mut chunk_tree = oc.split_off(chunk).unwrap();
+            let mut inside: Vec<_> = chunk_tree.into_iter().map(|item| item.id).collect();
+            inside.sort();
+            assert_eq!(inside, (10..40).collect::<Vec<_>>());
+            let mut ids = vec![1, 2, 3, 40];
+            assert_eq!(sorted_ids(&oc), ids);
+            assert_eq!(chunk_tree.straddle_mode(), mode);
+
+            // and merged back
+            assert!(oc.merge(chunk_tree).is_ok());
+            ids.extend(10..40);
+            ids.sort();
+            assert_eq!(sorted_ids(&oc), ids);
+            for &id in &ids {
+                assert!(oc.remove(id).is_some());
+            }
+            assert_eq!(oc.node_count(), 1);
+
+            chunk_tree = Octree::new(chunk);
+            assert!(oc.split_off(Aabb3 { min: chunk.max, max: chunk.min }).is_err());
+            assert!(chunk_tree.merge(Octree::new(world)).is_ok());
+        }
+    }
+
+    #[test]
+    fn should_track_items_entering_and_leaving_volume() {
+        for &mode in &MODES {
+            let mut oc = tree_with_mode(mode);
+            let mut rng = Lcg(47);
+            let mut reference = HashMap::new();
+            for id in 0..200 {
+                let bb = rng.next_bb();
+                oc.update(id, bb);
+                reference.insert(id, bb);
+            }
+
+            let mut tracker = OverlapTracker::new();
+            let mut inside: Vec<i32> = Vec::new();
+            for frame in 0..40 {
+                if frame % 10 == 5 {
+                    let bb = rng.next_bb();
+                    oc.update(frame, bb);
+                    reference.insert(frame, bb);
+                }
+
+                // slowly moving trigger volume
+                let t = frame as f64 * 0.2;
+                let sphere = Sphere {
+                    center: Point3::new(2.0 + t, 3.0, 4.0),
+                    radius: 1.0,
+                };
+                let revision = oc.revision();
+                let changes = tracker.update(&oc, sphere);
+                assert_eq!(oc.revision(), revision);
+
+                let mut expected: Vec<_> = reference.iter()
+                    .filter(|&(_, bb)| sphere.overlaps_aabb(bb))
+                    .map(|(&id, _)| id)
+                    .collect();
+                expected.sort();
+                let mut items = tracker.items().to_vec();
+                items.sort();
+                assert_eq!(items, expected);
+
+                let mut entered = changes.entered.clone();
+                entered.sort();
+                let mut exited = changes.exited.clone();
+                exited.sort();
+                let new: Vec<_> =
+                    expected.iter().cloned().filter(|id| !inside.contains(id)).collect();
+                let gone: Vec<_> =
+                    inside.iter().cloned().filter(|id| !expected.contains(id)).collect();
+                assert_eq!(entered, new);
+                assert_eq!(exited, gone);
+                inside = expected;
+            }
+
+            assert!(tracker.update(&oc, Sphere {
+                    center: Point3::new(2.0 + 39.0 * 0.2, 3.0, 4.0),
+                    radius: 1.0,
+                })
+                .is_empty());
+
+            tracker.reset();
+            assert!(tracker.items().is_empty());
+            let changes = tracker.update(&oc, Aabb3::new(Point3::new(-5.0, -5.0, -5.0),
+                                                         Point3::new(20.0, 20.0, 20.0)));
+            assert_eq!(changes.entered.len(), reference.len());
+        }
+    }
+
+    #[test]
+    fn should_find_items_with_any_key_index() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::BuildHasherDefault;
+
+        let world = Aabb3::new(Point3::new(0.0, 0.0, 0.0), Point3::new(10.0, 10.0, 10.0));
+        for &mode in &MODES {
+            let mut hashed = tree_with_mode(mode);
+            let mut dense: Octree<f64, u32> = Octree::with_dense_keys(1, world);
+            let hasher = BuildHasherDefault::<DefaultHasher>::default();
+            let mut custom: Octree<f64, i32, _> = Octree::with_hasher(1, world, hasher);
+            dense.set_straddle_mode(mode);
+            custom.set_straddle_mode(mode);
+
+            let mut rng = Lcg(43);
+            for _ in 0..300 {
+                let id = rng.next(60.0) as i32;
+                if rng.next(1.0) < 0.3 {
+                    let removed = hashed.remove(id).map(|item| item.id);
+                    assert_eq!(dense.remove(id as u32).map(|item| item.id as i32), removed);
+                    assert_eq!(custom.remove(id).map(|item| item.id), removed);
+                } else {
+                    let bb = rng.next_bb();
+                    hashed.update(id, bb);
+                    dense.update(id as u32, bb);
+                    custom.update(id, bb);
+                }
+            }
+
+            // the index does not affect the tree structure
+            let ids: Vec<i32> = hashed.into_iter().map(|item| item.id).collect();
+            assert_eq!(dense.into_iter().map(|item| item.id as i32).collect::<Vec<_>>(), ids);
+            assert_eq!(custom.into_iter().map(|item| item.id).collect::<Vec<_>>(), ids);
+
+            let mut part = dense.split_off(world).unwrap();
+            assert_eq!(dense.into_iter().count() + part.into_iter().count(), ids.len());
+            for id in 0..60 {
+                let expected = ids.contains(&(id as i32));
+                let removed = dense.remove(id).is_some() || part.remove(id).is_some();
+                assert_eq!(removed, expected);
+            }
+            assert!(dense.ctx.object_node.is_empty() && part.ctx.object_node.is_empty());
+        }
+    }
+
+    #[test]
+    fn ray_cast_should_find_nearest_exact_hit() {
+        // exact volume of every item is the largest sphere inside its box
+        let sphere_hit = |bb: &Aabb3<f64>, ray: &Ray3<f64>| -> Option<f64> {
+            let half = (bb.max - bb.min) * 0.5;
+            let radius = half.x.min(half.y).min(half.z);
+            let to_center = (bb.min + half) - ray.origin;
+            let along = to_center.dot(ray.direction);
+            let d2 = to_center.magnitude2() - along * along;
+            if d2 > radius * radius {
+                return None;
+            }
+            let t = along - (radius * radius - d2).sqrt();
+            if t >= 0.0 { Some(t) } else { None }
+        };
+
+        for &mode in &MODES {
+            let mut oc = tree_with_mode(mode);
+            let mut rng = Lcg(47);
+            let mut reference = Vec::new();
+            for id in 0..200 {
+                let bb = rng.next_bb();
+                oc.update(id, bb);
+                reference.push(bb);
+            }
+
+            let (mut calls, mut box_hits) = (0, 0);
+            for _ in 0..64 {
+                let origin = rng.next_point();
+                let target = rng.next_point();
+                let ray = Ray3::new(origin, (target - origin).normalize());
+
+                let hit = oc.ray_cast(ray, |item, ray| {
+                    calls += 1;
+                    sphere_hit(&item.bb, ray)
+                });
+
+                let expected = reference.iter()
+                    .enumerate()
+                    .filter_map(|(id, bb)| sphere_hit(bb, &ray).map(|t| (id as i32, t)))
+                    .fold(None, |acc: Option<(i32, f64)>, hit| match acc {
+                        Some(acc) if acc.1 <= hit.1 => Some(acc),
+                        _ => Some(hit),
+                    });
+                let found = hit.map(|hit| (hit.item().id, (hit.point() - origin).magnitude()));
+                match (found, expected) {
+                    (Some(found), Some(expected)) => {
+                        assert_eq!(found.0, expected.0);
+                        assert!((found.1 - expected.1).abs() < 1e-9);
+                    }
+                    (found, expected) => assert_eq!(found, expected),
+                }
+
+                box_hits += reference.iter()
+                    .filter(|bb| query::ray_entry(&ray, bb).is_some())
+                    .count();
+            }
+
+            // items behind the nearest hit are skipped
+            if mode == StraddleMode::Parent {
+                assert!(calls < box_hits);
+            }
+        }
+    }
+
+    /// Check the content bounds of the `node` subtree, returns the union of its items.
+    fn check_content(node: NodeRef<f64, i32>) -> Option<Aabb3<f64>> {
+        check_content_bounds(node, true)
+    }
+
+    /// Check that the content bounds of the `node` subtree contain its items, or equal their
+    /// union if `tight`.
+    fn check_content_bounds(node: NodeRef<f64, i32>, tight: bool) -> Option<Aabb3<f64>> {
+        let mut expected: Option<Aabb3<f64>> = None;
+        let mut add = |bb: Aabb3<f64>| {
+            expected = Some(match expected {
+                Some(e) => Aabb3::new(e.min, e.max).grow(bb.min).grow(bb.max),
+                None => bb,
+            });
+        };
+        for item in node.items() {
+            add(item.bb);
+        }
+        for child in node.children() {
+            if let Some(bb) = check_content_bounds(child, tight) {
+                add(bb);
+            }
+        }
+        if tight {
+            assert_eq!(node.content_bounds(), expected);
+        } else if let Some(expected) = expected {
+            let content = node.content_bounds().unwrap();
+            assert!(query::contains_bb(&content, &expected));
+        }
+        expected
+    }
+
+    /// Ids of all items in the `node` subtree.
+    fn subtree_ids(node: NodeRef<f64, i32>, ids: &mut Vec<i32>) {
+        ids.extend(node.items().iter().map(|item| item.id));
+        for child in node.children() {
+            subtree_ids(child, ids);
+        }
+    }
+
+    #[test]
+    fn should_keep_content_bounds_of_nodes() {
+        for &mode in &MODES {
+            let mut oc = tree_with_mode(mode);
+            let mut rng = Lcg(53);
+            for step in 0..400 {
+                let id = rng.next(80.0) as i32;
+                if rng.next(1.0) < 0.3 {
+                    oc.remove(id);
+                } else {
+                    oc.update(id, rng.next_bb());
+                }
+                if step % 50 == 0 {
+                    oc.rebalance_step(20);
+                }
+                check_content(oc.root_node());
+            }
+
+            let chunk_bb = Aabb3::new(Point3::new(5.0, 5.0, 5.0), Point3::new(10.0, 10.0, 10.0));
+            for id in 0..80 {
+                oc.remove(id);
+            }
+            let mut chunk = Octree::with_branch_size(1, chunk_bb);
+            chunk.set_straddle_mode(mode);
+            for id in 0..40 {
+                let p = Point3::new(5.0, 5.0, 5.0) +
+                        vec3(rng.next(4.0), rng.next(4.0), rng.next(4.0));
+                chunk.update(id, Aabb3::new(p, p + vec3(1.0, 1.0, 1.0)));
+            }
+            oc.update(100, rng.next_bb());
+            oc.merge(chunk).unwrap();
+            check_content(oc.root_node());
+
+            oc.shift_origin(vec3(1.0, 2.0, 3.0));
+            check_content(oc.root_node());
+
+            oc.remove(100);
+            for id in 0..80 {
+                oc.remove(id);
+            }
+            assert_eq!(check_content(oc.root_node()), None);
+        }
+    }
+
+    #[test]
+    fn select_lod_should_cover_every_item() {
+        for &mode in &MODES {
+            let mut oc = tree_with_mode(mode);
+            let mut rng = Lcg(59);
+            for id in 0..150 {
+                oc.update(id, rng.next_bb());
+            }
+            let camera = Point3::new(1.0, 1.0, 1.0);
+
+            let all: Vec<Lod<f64, i32>> = oc.select_lod(camera, 0.0).collect();
+            assert!(all.iter().all(|lod| match *lod {
+                Lod::Item(_) => true,
+                Lod::Proxy(_) => false,
+            }));
+            assert_eq!(all.len(), 150);
+
+            // the camera is inside the content of the root node, which is never a proxy then
+            let far = Point3::new(100.0, 100.0, 100.0);
+            let coarse: Vec<Lod<f64, i32>> = oc.select_lod(far, 1.0).collect();
+            assert_eq!(coarse.len(), 1);
+            match coarse[0] {
+                Lod::Proxy(node) => assert_eq!(node.id(), oc.root_node().id()),
+                Lod::Item(_) => panic!("expected the root proxy"),
+            }
+
+            let mut proxies = 0;
+            let mut ids = Vec::new();
+            for lod in oc.select_lod(camera, 0.5) {
+                match lod {
+                    Lod::Item(item) => ids.push(item.id),
+                    Lod::Proxy(node) => {
+                        proxies += 1;
+                        let content = node.content_bounds().unwrap();
+                        let size = (content.max - content.min).magnitude();
+                        assert!(size <= 0.5 * query::distance2(&content, camera).sqrt());
+                        subtree_ids(node, &mut ids);
+                    }
+                }
+            }
+            assert!(proxies > 0);
+            ids.sort();
+            ids.dedup();
+            assert_eq!(ids, (0..150).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn should_find_items_with_loose_content_bounds() {
+        for &mode in &MODES {
+            let mut oc = tree_with_mode(mode);
+            oc.set_tight_bounds(false);
+            assert!(!oc.tight_bounds());
+
+            let mut rng = Lcg(61);
+            let mut reference = HashMap::new();
+            for step in 0..400 {
+                let id = rng.next(80.0) as i32;
+                if rng.next(1.0) < 0.3 {
+                    oc.remove(id);
+                    reference.remove(&id);
+                } else {
+                    let bb = rng.next_bb();
+                    oc.update(id, bb);
+                    reference.insert(id, bb);
+                }
+
+                if step % 40 == 0 {
+                    check_content_bounds(oc.root_node(), false);
+
+                    let region = rng.next_bb();
+                    let mut found: Vec<i32> = oc.overlapping(region).map(|item| item.id).collect();
+                    found.sort();
+                    let mut expected: Vec<i32> = reference.iter()
+                        .filter(|&(_, bb)| bb.overlaps_aabb(&region))
+                        .map(|(&id, _)| id)
+                        .collect();
+                    expected.sort();
+                    assert_eq!(found, expected);
+                }
+            }
+
+            // a full pass without changes tightens every node
+            while !oc.rebalance_step(100) {}
+            check_content_bounds(oc.root_node(), true);
+
+            for id in 0..40 {
+                oc.remove(id);
+            }
+            oc.set_tight_bounds(true);
+            check_content_bounds(oc.root_node(), true);
+        }
+    }
+
+    #[test]
+    fn snapshots_should_keep_the_version_they_were_taken_at() {
+        for &mode in &MODES {
+            let mut oc = tree_with_mode(mode);
+            let mut rng = Lcg(67);
+            let mut snapshots = Vec::new();
+            for step in 0..400 {
+                let id = rng.next(80.0) as i32;
+                if rng.next(1.0) < 0.3 {
+                    oc.remove(id);
+                } else {
+                    oc.update(id, rng.next_bb());
+                }
+                if step % 50 == 0 {
+                    oc.rebalance_step(10);
+                }
+
+                if step % 20 == 0 {
+                    let snapshot = oc.snapshot();
+                    let items: Vec<(i32, Aabb3<f64>)> =
+                        oc.into_iter().map(|item| (item.id, item.bb)).collect();
+                    assert_eq!(snapshot.revision(), oc.revision());
+
+                    let region = rng.next_bb();
+                    let expected: Vec<i32> = oc.overlapping(region).map(|item| item.id).collect();
+                    let found = snapshot.overlapping(region).map(|item| item.id);
+                    assert_eq!(found.collect::<Vec<_>>(), expected);
+
+                    snapshots.push((snapshot, items));
+                }
+            }
+
+            // nodes changed after the last snapshot are copied again
+            let ids: Vec<i32> = oc.into_iter().map(|item| item.id).collect();
+            assert_eq!(oc.snapshot().iter().map(|item| item.id).collect::<Vec<_>>(), ids);
+            assert_eq!(oc.snapshot().iter().map(|item| item.id).collect::<Vec<_>>(), ids);
+
+            for (snapshot, items) in snapshots {
+                let found: Vec<(i32, Aabb3<f64>)> =
+                    snapshot.iter().map(|item| (item.id, item.bb)).collect();
+                assert_eq!(found, items);
+            }
+        }
+    }
+
+    #[test]
+    fn snapshot_should_be_readable_from_other_thread() {
+        use std::thread;
+
+        let mut oc = tree_with_mode(StraddleMode::Parent);
+        let mut rng = Lcg(71);
+        for id in 0..100 {
+            oc.update(id, rng.next_bb());
+        }
+
+        let snapshot = oc.snapshot();
+        let reader = thread::spawn(move || snapshot.iter().count());
+        for id in 0..50 {
+            oc.remove(id);
+        }
+        assert_eq!(reader.join().unwrap(), 100);
+        assert_eq!(oc.snapshot().iter().count(), 50);
+    }
+
+    #[test]
+    fn visible_items_should_hide_items_behind_occluders() {
+        for &mode in &MODES {
+            let mut oc = tree_with_mode(mode);
+            let viewpoint = Point3::new(0.5, 5.0, 5.0);
+
+            // wall across the tree, an item in front of it and one behind
+            oc.update(0, Aabb3::new(Point3::new(2.0, 0.0, 0.0), Point3::new(2.5, 10.0, 10.0)));
+            oc.update(1, Aabb3::new(Point3::new(1.0, 4.0, 4.0), Point3::new(1.5, 6.0, 6.0)));
+            oc.update(2, Aabb3::new(Point3::new(6.0, 4.0, 4.0), Point3::new(7.0, 6.0, 6.0)));
+            oc.update(3, Aabb3::new(Point3::new(0.0, 8.0, 4.0), Point3::new(0.2, 9.0, 6.0)));
+
+            let ids = |items: Vec<&Item<f64, i32>>| -> Vec<i32> {
+                items.into_iter().map(|item| item.id).collect()
+            };
+            assert_eq!(ids(oc.visible_items(viewpoint, 16, |item| item.id == 0)),
+                       vec![1, 0, 3]);
+            let mut all = ids(oc.visible_items(viewpoint, 16, |_| false));
+            all.sort();
+            assert_eq!(all, vec![0, 1, 2, 3]);
+        }
+
+        for &mode in &MODES {
+            let mut oc = tree_with_mode(mode);
+            let mut rng = Lcg(48);
+            let mut occluders = Vec::new();
+            for id in 0..150 {
+                let bb = rng.next_bb();
+                oc.update(id, bb);
+                if id % 3 == 0 {
+                    occluders.push(bb);
+                }
+            }
+
+            for _ in 0..8 {
+                let viewpoint = rng.next_point();
+                let visible = oc.visible_items(viewpoint, 32, |item| item.id % 3 == 0);
+
+                let distances: Vec<f64> =
+                    visible.iter().map(|item| query::distance2(&item.bb, viewpoint)).collect();
+                assert!(distances.windows(2).all(|pair| pair[0] <= pair[1]));
+
+                // every point of a hidden item must be behind some occluder
+                let visible: Vec<i32> = visible.into_iter().map(|item| item.id).collect();
+                for item in &oc {
+                    if visible.contains(&item.id) {
+                        continue;
+                    }
+                    let center = item.bb.min + (item.bb.max - item.bb.min) * 0.5;
+                    for &corner in &item.bb.to_corners() {
+                        let point = corner + (center - corner) * 0.01;
+                        let distance = (point - viewpoint).magnitude();
+                        let ray = Ray3::new(viewpoint, (point - viewpoint) / distance);
+                        assert!(occluders.iter().any(|bb| {
+                            !query::contains_point(bb, viewpoint) &&
+                            matches!(query::ray_entry(&ray, bb), Some(t) if t < distance)
+                        }));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "point-cloud")]
+    fn should_import_and_filter_point_clouds() {
+        use std::io::Cursor;
+
+        let xyz = "# scan\n0 0 0\n1.5,2,3 255 0 0\n\n// end\n4 5 6\n";
+        let points = point_cloud::read_xyz(Cursor::new(xyz)).unwrap();
+        assert_eq!(points,
+                   vec![Point3::new(0.0, 0.0, 0.0),
+                        Point3::new(1.5, 2.0, 3.0),
+                        Point3::new(4.0, 5.0, 6.0)]);
+        assert!(point_cloud::read_xyz(Cursor::new("1 2\n")).is_err());
+
+        // the same points as binary big endian floats after a face element, and as ASCII
+        let header = "ply\nformat binary_big_endian 1.0\nelement face 1\n\
+                      property list uchar int vertex_indices\nelement vertex 3\n\
+                      property uchar flags\nproperty float x\nproperty float y\n\
+                      property double z\nend_header\n";
+        let mut ply = header.as_bytes().to_vec();
+        ply.extend(&[2, 0, 0, 0, 7, 0, 0, 0, 8]);
+        for point in &points {
+            ply.push(1);
+            for &coord in &[point.x as f32, point.y as f32] {
+                let bits = coord.to_bits();
+                ply.extend(&[(bits >> 24) as u8, (bits >> 16) as u8, (bits >> 8) as u8,
+                             bits as u8]);
+            }
+            let bits = point.z.to_bits();
+            ply.extend((0..8).rev().map(|i| (bits >> (i * 8)) as u8));
+        }
+        assert_eq!(point_cloud::read_ply(Cursor::new(ply)).unwrap(), points);
+
+        let ascii = "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\n\
+                     property float y\nproperty float z\nend_header\n0 0 0\n1.5 2 3\n4 5 6\n";
+        assert_eq!(point_cloud::read_ply(Cursor::new(ascii)).unwrap(), points);
+
+        // a dense 4x4x4 grid with unit spacing and a few distant points
+        let mut points = Vec::new();
+        for i in 0..64 {
+            points.push(Point3::new((i % 4) as f64, (i / 4 % 4) as f64, (i / 16) as f64));
+        }
+        points.push(Point3::new(20.0, 20.0, 20.0));
+        points.push(Point3::new(-10.0, 20.0, 0.0));
+        points.push(Point3::new(-10.0, 20.5, 0.0));
+
+        let mut tree = point_cloud::index(&points, 4).unwrap();
+        assert_eq!(tree.into_iter().count(), points.len());
+        assert!(point_cloud::index::<f64>(&[], 4).is_err());
+
+        let density = point_cloud::density(&tree);
+        assert_eq!(density[0].points, points.len());
+        assert_eq!(density[0].bounds, tree.root_node().bounds());
+        for node in &density {
+            let size = node.bounds.max - node.bounds.min;
+            assert!((node.density * size.x * size.y * size.z - node.points as f64).abs() < 1e-9);
+            let inside = points.iter()
+                .filter(|&&p| query::contains_point(&node.bounds, p))
+                .count();
+            assert!(node.points <= inside);
+        }
+
+        let mut removed = point_cloud::remove_outliers(&mut tree, 1.0, 2);
+        removed.sort();
+        assert_eq!(removed, vec![64, 65, 66]);
+        assert_eq!(tree.into_iter().count(), 64);
+
+        // cells of 2 hold 8 points of the grid each
+        let centroids = point_cloud::voxel_downsample(&tree, 2.0).unwrap();
+        assert_eq!(centroids.len(), 8);
+        assert_eq!(centroids[0], Point3::new(0.5, 0.5, 0.5));
+        assert_eq!(centroids[1], Point3::new(2.5, 0.5, 0.5));
+        assert_eq!(centroids[7], Point3::new(2.5, 2.5, 2.5));
+        for &size in &[0.0, -1.0, f64::NAN] {
+            assert_eq!(point_cloud::voxel_downsample(&tree, size),
+                       Err(Error::InvalidVoxelSize));
+        }
+
+        let far = point_cloud::index(&[Point3::new(0.0, 0.0, 0.0), Point3::new(1e20, 1e20, 1e20)],
+                                     4)
+            .unwrap();
+        assert_eq!(point_cloud::voxel_downsample(&far, 1.0), Err(Error::CoordinateOutOfRange));
+    }
+
+    #[test]
+    fn octree_file_should_answer_queries_like_the_tree() {
+        use std::fs;
+        use std::io::Cursor;
+        use file::{self, OctreeFile};
+
+        for (n, &mode) in MODES.iter().enumerate() {
+            let mut oc = tree_with_mode(mode);
+            let mut rng = Lcg(50);
+            for id in 0..300 {
+                oc.update(id, rng.next_bb());
+            }
+            // an item outside of the world stays in the root node
+            oc.update(300, Aabb3::new(Point3::new(12.0, 0.0, 0.0), Point3::new(13.0, 1.0, 1.0)));
+
+            let path = ::std::env::temp_dir()
+                .join(format!("octree-file-test-{}-{}.bin", ::std::process::id(), n));
+            file::write_with_page_size(&oc, 1024, fs::File::create(&path).unwrap()).unwrap();
+            let mut disk = OctreeFile::<_, f64, i32>::open(&path).unwrap();
+
+            assert_eq!(disk.item_count(), 301);
+            assert_eq!(disk.node_count(), oc.node_count());
+            assert_eq!(disk.bounds().unwrap(), oc.root_node().bounds());
+
+            // a small region is answered without loading the whole tree
+            let ids = |items: Vec<&Item<f64, i32>>| -> Vec<i32> {
+                let mut ids: Vec<i32> = items.into_iter().map(|item| item.id).collect();
+                ids.sort();
+                ids
+            };
+            let corner = Aabb3::new(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 1.0, 1.0));
+            let found = disk.overlapping(corner).unwrap();
+            assert_eq!(ids(found.iter().collect()), ids(oc.overlapping(corner).collect()));
+            assert!(disk.loaded_node_count() < disk.node_count());
+
+            for _ in 0..20 {
+                let region = rng.next_bb();
+                let found = disk.overlapping(region).unwrap();
+                assert_eq!(ids(found.iter().collect()), ids(oc.overlapping(region).collect()));
+
+                let origin = rng.next_point();
+                let ray = Ray3::new(origin, (rng.next_point() - origin).normalize());
+                let expected = oc.ray_cast(ray, |item, ray| query::ray_entry(ray, &item.bb));
+                let hit = disk.ray_cast(ray, |item, ray| query::ray_entry(ray, &item.bb)).unwrap();
+                assert_eq!(hit.map(|(point, _)| point), expected.map(|hit| hit.point()));
+            }
+
+            // least recently used nodes are forgotten beyond the cache limit
+            disk.set_cache_limit(3);
+            assert_eq!(disk.loaded_node_count(), 3);
+            let region = Aabb3::new(Point3::new(2.0, 2.0, 2.0), Point3::new(8.0, 8.0, 8.0));
+            let found = disk.overlapping(region).unwrap();
+            assert_eq!(ids(found.iter().collect()), ids(oc.overlapping(region).collect()));
+            assert!(disk.loaded_node_count() <= 3);
+
+            disk.clear_cache();
+            assert_eq!(disk.loaded_node_count(), 0);
+            assert_eq!(disk.overlapping(Aabb3::new(Point3::new(12.5, 0.5, 0.5),
+                                                   Point3::new(12.5, 0.5, 0.5)))
+                           .unwrap()
+                           .len(),
+                       1);
+
+            drop(disk);
+            fs::remove_file(&path).unwrap();
+        }
+
+        assert!(OctreeFile::<_, f64, i32>::new(Cursor::new(vec![0; 64])).is_err());
+        let mut oc = tree_with_mode(StraddleMode::Parent);
+        assert!(file::write_with_page_size(&oc, 100, Vec::new()).is_err());
+
+        // corrupted sizes and offsets are rejected instead of trusted
+        let mut rng = Lcg(51);
+        for id in 0..100 {
+            oc.update(id, rng.next_bb());
+        }
+        let mut bytes = Vec::new();
+        file::write_with_page_size(&oc, 1024, &mut bytes).unwrap();
+        // the root node starts the page after the header
+        let root = 1024;
+        let corrupt = |at: usize, value: &[u8]| {
+            let mut bytes = bytes.clone();
+            bytes[at..at + value.len()].copy_from_slice(value);
+            OctreeFile::<_, f64, i32>::new(Cursor::new(bytes))
+        };
+        let everything = Aabb3::new(Point3::new(0.0, 0.0, 0.0), Point3::new(10.0, 10.0, 10.0));
+        let invalid_data = |result: ::std::io::Result<Vec<Item<f64, i32>>>| {
+            result.unwrap_err().kind() == ::std::io::ErrorKind::InvalidData
+        };
+
+        // item count of the root node
+        let mut disk = corrupt(root + 52, &[0xff; 4]).unwrap();
+        assert!(invalid_data(disk.overlapping(everything)));
+        // first child of the root node pointing back to the root node
+        let mut disk = corrupt(root + 104, &[0, 4, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert!(invalid_data(disk.overlapping(everything)));
+        // node count in the header
+        assert!(corrupt(24, &[0xff; 8]).is_err());
+
+        // ids that do not fit the key type are rejected instead of truncated
+        let mut oc: Octree<f64, u64> = Octree::with_branch_size(4, everything);
+        oc.update(300, Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)));
+        oc.update(44, Aabb3::new(Point3::new(3.0, 3.0, 3.0), Point3::new(4.0, 4.0, 4.0)));
+        let mut bytes = Vec::new();
+        file::write(&oc, &mut bytes).unwrap();
+        let mut disk = OctreeFile::<_, f64, u8>::new(Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(disk.overlapping(everything).unwrap_err().kind(),
+                   ::std::io::ErrorKind::InvalidData);
+        let mut disk = OctreeFile::<_, f64, u64>::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(disk.overlapping(everything).unwrap().len(), 2);
+
+        let mut oc = tree_with_mode(StraddleMode::Parent);
+        oc.update(-5, Aabb3::new(Point3::new(1.0, 1.0, 1.0), Point3::new(2.0, 2.0, 2.0)));
+        let mut bytes = Vec::new();
+        file::write(&oc, &mut bytes).unwrap();
+        let mut disk = OctreeFile::<_, f64, i64>::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(disk.overlapping(everything).unwrap()[0].id, -5);
+
+        // integer coordinates beyond the precision of f64 are not written
+        let big = 1i64 << 60;
+        let world = Aabb3::new(Point3::new(0, 0, 0), Point3::new(big, big, big));
+        let mut oc: Octree<i64, i32> = Octree::with_branch_size(4, world);
+        oc.update(1, Aabb3::new(Point3::new(0, 0, 0), Point3::new(1, 1, 1)));
+        let mut bytes = Vec::new();
+        file::write(&oc, &mut bytes).unwrap();
+        oc.update(2, Aabb3::new(Point3::new(1, 1, 1), Point3::new(big + 1, 1, 1)));
+        let mut bytes = Vec::new();
+        assert_eq!(file::write(&oc, &mut bytes).unwrap_err().kind(),
+                   ::std::io::ErrorKind::InvalidInput);
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn rebalance_should_push_items_down_from_branch_nodes() {
+        for &mode in &MODES {
+            let mut oc = Octree::with_branch_size(2,
+                                                  Aabb3::new(Point3::new(0.0, 0.0, 0.0),
+                                                             Point3::new(10.0, 10.0, 10.0)));
+            oc.set_straddle_mode(mode);
+            for &(id, x) in &[(1, 1.0), (2, 2.0), (3, 3.0), (4, 8.0)] {
+                let p = Point3::new(x, x, x);
+                oc.update(id, Aabb3::new(p, p + vec3(0.5, 0.5, 0.5)));
+            }
+
+            let mut steps = 0;
+            while !oc.rebalance_step(1) {
+                steps += 1;
+                assert!(steps < 100, "rebalancing did not converge");
+            }
+            assert!(oc.root_node().items().iter().all(|item| item.id != 4));
+            assert_eq!(sorted_ids(&oc), vec![1, 2, 3, 4]);
+
+            // steps that change nothing keep the revision
+            let revision = oc.revision();
+            assert!(oc.rebalance_step(oc.node_count()));
+            assert_eq!(oc.revision(), revision);
+        }
+    }
 }
 
@@ -0,0 +1,17 @@
+/// Placement of items that cross the boundaries of child nodes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum StraddleMode {
+    /// Item is kept in the smallest node that fully contains it.
+    ///
+    /// Every item is stored exactly once, and updates only touch the nodes the item moves
+    /// between. Large items crossing the center of the world end up near the root and are
+    /// tested by every query.
+    #[default]
+    Parent,
+    /// Item is referenced from every leaf node it overlaps.
+    ///
+    /// Works better for scenes dominated by long thin items, such as roads or walls, because
+    /// queries only test the items in the nodes they actually visit. Query iterators skip the
+    /// duplicate references. Updates remove and re-insert the item from the root.
+    Children,
+}
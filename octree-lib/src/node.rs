@@ -1,20 +1,108 @@
-use std::collections::HashMap;
-use std::collections::hash_map::Entry;
 use std::ptr;
+use std::mem;
 use std::fmt;
 use std::cmp::Eq;
-use std::hash::Hash;
+use std::hash::{Hash, BuildHasher};
+use prelude::*;
 use cgmath::*;
 use collision::*;
 use super::item::Item;
+use super::event::{Event, NodeId};
+use super::mode::StraddleMode;
+use super::index::KeyIndex;
+
+/// Tree-wide state used by nodes during modification.
+pub struct Context<S, K, H>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    pub branch_size: usize,
+    pub mode: StraddleMode,
+    /// Node of every item. With `StraddleMode::Children` this is any one of the nodes that
+    /// reference the item.
+    pub object_node: KeyIndex<K, *mut Node<S, K>, H>,
+    pub events: Option<Vec<Event<K>>>,
+    /// Recompute content bounds when items are removed or shrink, see
+    /// `Octree::set_tight_bounds`.
+    pub tight_bounds: bool,
+    /// Released nodes kept for reuse, so that subdividing does not allocate.
+    pub pool: Vec<Box<Node<S, K>>>,
+    pub live_nodes: usize,
+    next_node_id: u64,
+}
+
+impl<S, K, H> Context<S, K, H>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    pub fn new(branch_size: usize, object_node: KeyIndex<K, *mut Node<S, K>, H>)
+               -> Context<S, K, H> {
+        Context {
+            branch_size: branch_size,
+            mode: StraddleMode::Parent,
+            object_node: object_node,
+            events: None,
+            tight_bounds: true,
+            pool: Vec::new(),
+            live_nodes: 0,
+            next_node_id: 0,
+        }
+    }
+
+    /// Take a node from the pool, or allocate a new one if the pool is empty.
+    pub fn alloc_node(&mut self, bb: Aabb3<S>) -> Box<Node<S, K>> {
+        let id = self.next_node_id();
+        self.live_nodes += 1;
+
+        match self.pool.pop() {
+            Some(mut node) => {
+                node.reset(id, bb);
+                node
+            }
+            None => Box::new(Node::new(id, bb)),
+        }
+    }
+
+    /// Return a leaf node to the pool.
+    pub fn release_node(&mut self, node: Box<Node<S, K>>) {
+        debug_assert!(!node.is_branch, "released node would leak its children");
+        self.live_nodes -= 1;
+        self.pool.push(node);
+    }
+
+    pub fn next_node_id(&mut self) -> NodeId {
+        let id = NodeId(self.next_node_id);
+        self.next_node_id += 1;
+        id
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.events.is_some()
+    }
+
+    pub fn emit(&mut self, event: Event<K>) {
+        if let Some(ref mut events) = self.events {
+            events.push(event);
+        }
+    }
+}
 
 pub struct Node<S, K>
     where S: BaseNum,
           K: Clone + Eq + Hash
 {
+    pub id: NodeId,
     pub is_branch: bool,
     pub leafs: Vec<Item<S, K>>,
     pub bb: Aabb3<S>,
+    /// Union of the item volumes in the subtree, `None` if there are no items.
+    ///
+    /// Items that cross the node boundary are included whole, so this can extend beyond
+    /// `bb`. Without `Context::tight_bounds` it is only guaranteed to contain the items.
+    pub content: Option<Aabb3<S>>,
+    /// The subtree changed since the last snapshot, see `snapshot::build`. Ancestors of a
+    /// dirty node are dirty too.
+    pub dirty: bool,
 
     center: Point3<S>,
     parent: *mut Node<S, K>,
@@ -27,9 +115,12 @@ impl<S, K> fmt::Debug for Node<S, K>
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Node")
+            .field("id", &self.id)
             .field("is_branch", &self.is_branch)
             .field("leafs", &self.leafs)
             .field("bb", &self.bb)
+            .field("content", &self.content)
+            .field("dirty", &self.dirty)
             .field("center", &self.center)
             .field("parent", &self.parent)
             .field("nodes", &self.nodes)
@@ -41,79 +132,456 @@ impl<S, K> Node<S, K>
     where S: BaseNum,
           K: Clone + Eq + Hash
 {
-    pub fn new(bb: Aabb3<S>) -> Node<S, K> {
+    pub fn new(id: NodeId, bb: Aabb3<S>) -> Node<S, K> {
         Node {
+            id: id,
             is_branch: false,
             bb: bb,
-            center: bb.center(),
+            content: None,
+            dirty: true,
+            center: midpoint(&bb),
             leafs: Vec::new(),
             parent: ptr::null_mut(),
             nodes: [ptr::null_mut(); 8],
         }
     }
 
+    /// Reinitialize a pooled node, keeping the allocated item storage.
+    fn reset(&mut self, id: NodeId, bb: Aabb3<S>) {
+        self.id = id;
+        self.is_branch = false;
+        self.leafs.clear();
+        self.bb = bb;
+        self.content = None;
+        self.dirty = true;
+        self.center = midpoint(&bb);
+        self.parent = ptr::null_mut();
+    }
+
     pub fn is_empty(&self) -> bool {
         self.leafs.is_empty() && !self.is_branch
     }
 
-    pub fn insert(&mut self,
-                  branch_size: usize,
-                  id: K,
-                  bb: Aabb3<S>,
-                  object_node: &mut HashMap<K, *mut Node<S, K>>)
-        where S: BaseFloat
+    pub fn insert<H>(&mut self, id: K, bb: Aabb3<S>, ctx: &mut Context<S, K, H>)
+        where H: BuildHasher
     {
         // println!("insert");
 
-        if self.leafs.len() >= branch_size {
+        if self.leafs.len() >= ctx.branch_size {
             if let Some(index) = self.select_node_index(&bb) {
                 if !self.is_branch {
                     // println!("not branch and leafs.len >= max");
-                    self.subdivide();
-                    self.move_leafs_to_children(branch_size, object_node);
+                    self.subdivide(ctx);
+                    self.move_leafs_to_children(ctx);
                     // println!("branch = true");
                     self.is_branch = true;
                 }
 
                 // println!("selected branch index {:?}", index);
-                self.get_node_mut(index).insert(branch_size, id, bb, object_node);
+                self.get_node_mut(index).insert(id, bb, ctx);
                 return;
             }
         }
 
         // println!("add leaf {:?}", self.leafs.len());
 
+        self.push_leaf(id, bb, ctx);
+    }
+
+    /// Insert item into every leaf node it overlaps, used with `StraddleMode::Children`.
+    ///
+    /// The root node keeps items that are not inside the world.
+    pub fn insert_overlapping<H>(&mut self, id: K, bb: Aabb3<S>, ctx: &mut Context<S, K, H>)
+        where H: BuildHasher
+    {
+        if self.parent.is_null() && !self.contains_bb(&bb) {
+            self.push_leaf(id, bb, ctx);
+            return;
+        }
+
+        if !self.is_branch {
+            if self.leafs.len() < ctx.branch_size || !self.should_split_overlapping(&bb) {
+                self.push_leaf(id, bb, ctx);
+                return;
+            }
+
+            self.subdivide(ctx);
+            self.is_branch = true;
+            self.move_leafs_to_overlapping_children(ctx);
+        }
+
+        for i in 0..8 {
+            if overlaps(&self.get_node(i).bb, &bb) {
+                self.get_node_mut(i).insert_overlapping(id.clone(), bb, ctx);
+            }
+        }
+    }
+
+    /// Splitting the node helps only if at least one of the items would end up in fewer
+    /// than all children.
+    fn should_split_overlapping(&self, bb: &Aabb3<S>) -> bool {
+        if !self.can_subdivide() {
+            return false;
+        }
+
+        let fits_one_child = |other: &Aabb3<S>| {
+            match clip(other, &self.bb) {
+                Some(clipped) => self.select_node_index(&clipped).is_some(),
+                None => false,
+            }
+        };
+
+        fits_one_child(bb) || self.leafs.iter().any(|leaf| fits_one_child(&leaf.bb))
+    }
+
+    fn move_leafs_to_overlapping_children<H>(&mut self, ctx: &mut Context<S, K, H>)
+        where H: BuildHasher
+    {
+        let mut i = 0;
+        while i < self.leafs.len() {
+            // the root node retains items outside of the world
+            if self.parent.is_null() && !self.contains_bb(&self.leafs[i].bb) {
+                i += 1;
+                continue;
+            }
+
+            let item = self.leafs.remove(i);
+            self.touch();
+
+            if ctx.is_recording() {
+                ctx.emit(Event::ItemRemoved {
+                    id: item.id.clone(),
+                    node: self.id,
+                });
+            }
+
+            for index in 0..8 {
+                if overlaps(&self.get_node(index).bb, &item.bb) {
+                    self.get_node_mut(index).insert_overlapping(item.id.clone(), item.bb, ctx);
+                }
+            }
+        }
+    }
+
+    /// Remove all references to the item from this node and children that overlap `bb`,
+    /// used with `StraddleMode::Children`.
+    ///
+    /// Empty nodes are not cleaned up, use `collapse_empty_overlapping` afterwards.
+    pub fn remove_overlapping<H>(&mut self,
+                                 id: &K,
+                                 bb: &Aabb3<S>,
+                                 ctx: &mut Context<S, K, H>)
+                                 -> Option<Item<S, K>>
+        where H: BuildHasher
+    {
+        let mut removed = self.take_leaf(id);
+
+        if removed.is_some() && ctx.is_recording() {
+            ctx.emit(Event::ItemRemoved {
+                id: id.clone(),
+                node: self.id,
+            });
+        }
+
+        if self.is_branch {
+            for i in 0..8 {
+                if overlaps(&self.get_node(i).bb, bb) {
+                    let child_removed = self.get_node_mut(i).remove_overlapping(id, bb, ctx);
+                    if removed.is_none() {
+                        removed = child_removed;
+                    }
+                }
+            }
+        }
+
+        if removed.is_some() {
+            self.shrink_content(ctx);
+        }
+
+        removed
+    }
+
+    /// Collapse all branches overlapping `bb` whose children became empty.
+    pub fn collapse_empty_overlapping<H>(&mut self, bb: &Aabb3<S>, ctx: &mut Context<S, K, H>)
+        where H: BuildHasher
+    {
+        if !self.is_branch {
+            return;
+        }
+
+        for i in 0..8 {
+            if overlaps(&self.get_node(i).bb, bb) {
+                self.get_node_mut(i).collapse_empty_overlapping(bb, ctx);
+            }
+        }
+
+        self.collapse_if_children_empty(ctx);
+    }
+
+    /// Fix the structure of this node assuming its children are already balanced, returns
+    /// `true` if the node was changed.
+    ///
+    /// Leaf nodes holding more than `branch_size` items are subdivided if that moves some
+    /// of the items down, and branch nodes whose leaf children hold no more than
+    /// `branch_size` items in total are collapsed. Other branch nodes move the items that
+    /// fit into a child down, since `insert` keeps items in a branch until it is full.
+    pub fn rebalance<H>(&mut self, ctx: &mut Context<S, K, H>) -> bool
+        where H: BuildHasher
+    {
+        if self.is_branch {
+            if !self.can_merge_children(ctx) {
+                return self.push_down_leafs(ctx);
+            }
+
+            self.pull_up_children_leafs(ctx);
+            self.collapse_if_children_empty(ctx);
+            return true;
+        }
+
+        if self.leafs.len() <= ctx.branch_size {
+            return false;
+        }
+
+        if ctx.mode == StraddleMode::Children {
+            if !self.should_split_overlapping(&self.leafs[0].bb) {
+                return false;
+            }
+
+            self.subdivide(ctx);
+            self.is_branch = true;
+            self.move_leafs_to_overlapping_children(ctx);
+            return true;
+        }
+
+        if !self.leafs.iter().any(|leaf| self.select_node_index(&leaf.bb).is_some()) {
+            return false;
+        }
+
+        self.subdivide(ctx);
+        self.move_leafs_to_children(ctx);
+        self.is_branch = true;
+        true
+    }
+
+    /// Move the items of this branch node that fit into children down, returns `true` if
+    /// any item was moved.
+    fn push_down_leafs<H>(&mut self, ctx: &mut Context<S, K, H>) -> bool
+        where H: BuildHasher
+    {
+        let count = self.leafs.len();
+        if ctx.mode == StraddleMode::Children {
+            self.move_leafs_to_overlapping_children(ctx);
+        } else {
+            self.move_leafs_to_children(ctx);
+        }
+        self.leafs.len() != count
+    }
+
+    /// Returns `true` if all children are leafs, and all items fit into this node.
+    fn can_merge_children<H>(&self, ctx: &Context<S, K, H>) -> bool {
+        let mut ids: Vec<&K> = self.leafs.iter().map(|leaf| &leaf.id).collect();
+        if ids.len() > ctx.branch_size {
+            return false;
+        }
+
+        for i in 0..8 {
+            let child = self.get_node(i);
+            if child.is_branch {
+                return false;
+            }
+
+            for leaf in &child.leafs {
+                // items referenced from several children are counted once
+                if !ids.contains(&&leaf.id) {
+                    ids.push(&leaf.id);
+                    if ids.len() > ctx.branch_size {
+                        return false;
+                    }
+                }
+            }
+        }
+
+        true
+    }
+
+    fn pull_up_children_leafs<H>(&mut self, ctx: &mut Context<S, K, H>)
+        where H: BuildHasher
+    {
+        self.touch();
+        for i in 0..8 {
+            let mut leafs = mem::take(&mut self.get_node_mut(i).leafs);
+
+            for item in leafs.drain(..) {
+                if self.leafs.iter().any(|leaf| leaf.id == item.id) {
+                    // the index may point to the child that is about to be removed
+                    ctx.object_node.insert(item.id, self);
+                    continue;
+                }
+
+                self.push_leaf(item.id, item.bb, ctx);
+            }
+
+            // keep the storage for when the child is reused from the pool
+            self.get_node_mut(i).leafs = leafs;
+        }
+    }
+
+    /// Find the empty leaf node with exactly `bb` volume, subdividing empty leaf nodes on
+    /// the way down.
+    ///
+    /// Nodes subdivided on the way are left in place if no such node exists, and should be
+    /// cleaned up with `collapse_empty_overlapping`.
+    pub fn find_empty_node<H>(&mut self, bb: &Aabb3<S>, ctx: &mut Context<S, K, H>)
+                              -> Option<*mut Node<S, K>>
+        where H: BuildHasher
+    {
+        if self.bb == *bb {
+            return if self.is_empty() { Some(self) } else { None };
+        }
+
+        let index = self.select_node_index(bb)?;
+
+        if !self.is_branch {
+            if !self.leafs.is_empty() {
+                return None;
+            }
+
+            self.subdivide(ctx);
+            self.is_branch = true;
+        }
+
+        self.get_node_mut(index).find_empty_node(bb, ctx)
+    }
+
+    /// Move the items and children of `other` root node into this empty node of the same
+    /// volume, returns the items of `other` that are outside of its volume.
+    pub fn adopt<H>(&mut self,
+                    other: &mut Node<S, K>,
+                    ctx: &mut Context<S, K, H>)
+                    -> Vec<Item<S, K>>
+        where H: BuildHasher
+    {
+        let mut outside = Vec::new();
+        for item in other.leafs.drain(..) {
+            if self.contains_bb(&item.bb) {
+                self.leafs.push(item);
+            } else {
+                outside.push(item);
+            }
+        }
+
+        if other.is_branch {
+            for i in 0..8 {
+                let child = other.take_node(i);
+                self.set_node(i, child);
+            }
+            other.is_branch = false;
+            self.is_branch = true;
+        }
+
+        self.touch();
+        self.refresh_content();
+        self.index_adopted(ctx);
+        outside
+    }
+
+    /// Give new identifiers to the children of this node, and add the items of the subtree
+    /// to the object node index.
+    fn index_adopted<H>(&mut self, ctx: &mut Context<S, K, H>)
+        where H: BuildHasher
+    {
+        // snapshots of the other tree do not apply here
+        self.dirty = true;
+
+        for i in 0..self.leafs.len() {
+            let id = self.leafs[i].id.clone();
+            ctx.object_node.insert(id.clone(), self);
+
+            if ctx.is_recording() {
+                ctx.emit(Event::ItemInserted {
+                    id: id,
+                    node: self.id,
+                });
+            }
+        }
+
+        if !self.is_branch {
+            return;
+        }
+
+        for i in 0..8 {
+            self.get_node_mut(i).id = ctx.next_node_id();
+        }
+        ctx.live_nodes += 8;
+
+        if ctx.is_recording() {
+            let children = self.child_ids();
+            ctx.emit(Event::Subdivided {
+                node: self.id,
+                children: children,
+            });
+        }
+
+        for i in 0..8 {
+            self.get_node_mut(i).index_adopted(ctx);
+        }
+    }
+
+    /// Add item to this node and point the object node index to it.
+    fn push_leaf<H>(&mut self, id: K, bb: Aabb3<S>, ctx: &mut Context<S, K, H>)
+        where H: BuildHasher
+    {
         self.leafs.push(Item {
             bb: bb,
             id: id.clone(),
         });
+        self.touch();
+        self.grow_content(&bb);
 
         // update item's parent node in object node index
-        match object_node.entry(id) {
-            Entry::Vacant(e) => {
-                e.insert(self);
-            }
-            Entry::Occupied(mut e) => *e.get_mut() = self,
-        };
+        let previous = ctx.object_node.insert(id.clone(), self);
+
+        if ctx.is_recording() {
+            let event = match previous {
+                // items referenced from multiple nodes are reported per node
+                Some(_) if ctx.mode == StraddleMode::Children => {
+                    Event::ItemInserted {
+                        id: id,
+                        node: self.id,
+                    }
+                }
+                // previous node is still alive, because it is cleaned up only after insert
+                Some(node) => {
+                    Event::ItemMoved {
+                        id: id,
+                        from: unsafe { (*node).id },
+                        to: self.id,
+                    }
+                }
+                None => {
+                    Event::ItemInserted {
+                        id: id,
+                        node: self.id,
+                    }
+                }
+            };
+            ctx.emit(event);
+        }
     }
 
     /// This must be called only if it is known that `id` exists in this node.
     ///
-    /// The track of object nodes is kept in `object_node`.
-    pub fn update(&mut self,
-                  branch_size: usize,
-                  id: K,
-                  bb: Aabb3<S>,
-                  object_node: &mut HashMap<K, *mut Node<S, K>>)
-        where S: BaseFloat,
-              K: fmt::Debug
+    /// The track of object nodes is kept in `ctx.object_node`.
+    pub fn update<H>(&mut self, id: K, bb: Aabb3<S>, ctx: &mut Context<S, K, H>)
+        where K: fmt::Debug,
+              H: BuildHasher
     {
         // println!("update id {:?}", id);
 
         if self.can_contain(&bb) {
             // println!("can contain {:?}", bb);
 
-            if self.is_branch || self.leafs.len() >= branch_size {
+            if self.is_branch || self.leafs.len() >= ctx.branch_size {
                 // println!("is branch || leafs > max");
 
                 // if we are in branch that means item is crossing octree boundary
@@ -121,19 +589,22 @@ impl<S, K> Node<S, K>
                 if let Some(index) = self.select_node_index(&bb) {
                     // println!("found new subnode");
 
-                    self.remove(id.clone())
+                    // this node is not cleaned up even if it becomes empty, because
+                    // the item is moved to its child
+                    self.take_leaf(&id)
                         .expect("failed to remove leaf that was known to exist");
+                    self.shrink_content(ctx);
                     // object node will be updated when item is inserted somewhere
 
                     if !self.is_branch {
                         // println!("not branch yet");
-                        self.subdivide();
-                        self.move_leafs_to_children(branch_size, object_node);
+                        self.subdivide(ctx);
+                        self.move_leafs_to_children(ctx);
                         // println!("branch = true");
                         self.is_branch = true;
                     }
 
-                    self.get_node_mut(index).insert(branch_size, id, bb, object_node);
+                    self.get_node_mut(index).insert(id, bb, ctx);
                     return;
                 }
             }
@@ -141,52 +612,144 @@ impl<S, K> Node<S, K>
             // println!("update leaf item bb");
             // if not is branch or can't be moved to a child, simply update bb
             self.get_leaf_by_id_unchecked_mut(id).bb = bb;
+            self.touch();
+            if ctx.tight_bounds {
+                self.refresh_content();
+            } else {
+                self.grow_content(&bb);
+            }
             return;
         }
 
         // does not fit into this node
         // println!("does not fit in");
 
-        for i in 0..self.leafs.len() {
-            if unsafe { self.leafs.get_unchecked(i) }.id == id {
-                // println!("swap remove {:?}", id);
-                self.leafs.swap_remove(i);
-                break;
-            }
-        }
+        self.take_leaf(&id);
+        self.shrink_content(ctx);
         // object node will be updated when item is inserted somewhere
 
         // always find new parent or at least the root
-        self.find_new_parent(&bb).insert(branch_size, id, bb, object_node);
+        self.find_new_parent(&bb).insert(id, bb, ctx);
 
         if !self.is_branch && self.leafs.is_empty() {
-            self.try_cleanup_parent();
+            self.try_cleanup_parent(ctx);
         }
     }
 
     /// Note that remove *does not* take care of removing node from `object_node` index.
     ///
     /// It should be done by the caller.
-    pub fn remove(&mut self, id: K) -> Option<Item<S, K>> {
-        let mut leaf = None;
+    pub fn remove<H>(&mut self, id: K, ctx: &mut Context<S, K, H>) -> Option<Item<S, K>>
+        where H: BuildHasher
+    {
+        let leaf = self.take_leaf(&id);
+        if leaf.is_some() {
+            self.shrink_content(ctx);
+        }
 
+        if leaf.is_some() && !self.is_branch && self.leafs.is_empty() {
+            self.try_cleanup_parent(ctx);
+        }
+
+        leaf
+    }
+
+    /// Remove leaf item without cleaning up empty nodes.
+    ///
+    /// Remaining items keep their order, which makes the iteration order independent of
+    /// removal history.
+    fn take_leaf(&mut self, id: &K) -> Option<Item<S, K>> {
         for i in 0..self.leafs.len() {
-            if unsafe { self.leafs.get_unchecked(i) }.id == id {
-                leaf = Some(self.leafs.swap_remove(i));
+            if unsafe { self.leafs.get_unchecked(i) }.id == *id {
+                self.touch();
+                return Some(self.leafs.remove(i));
+            }
+        }
+
+        None
+    }
+
+    /// Mark this node and its ancestors as changed since the last snapshot.
+    fn touch(&mut self) {
+        let mut node: *mut Node<S, K> = self;
+        while let Some(current) = unsafe { node.as_mut() } {
+            if current.dirty {
                 break;
             }
+
+            current.dirty = true;
+            node = current.parent;
         }
+    }
 
-        if leaf.is_some() && !self.is_branch && self.leafs.is_empty() {
-            self.try_cleanup_parent();
+    /// Replace the content bounds of this node, without updating the ancestors.
+    pub fn set_content(&mut self, content: Option<Aabb3<S>>) {
+        if self.content != content {
+            self.content = content;
+            self.touch();
         }
+    }
 
-        leaf
+    /// Extend the content bounds of this node and its ancestors with `bb`.
+    fn grow_content(&mut self, bb: &Aabb3<S>) {
+        let mut node: *mut Node<S, K> = self;
+        while let Some(current) = unsafe { node.as_mut() } {
+            let grown = Some(current.content.map_or(*bb, |content| union(&content, bb)));
+            if current.content == grown {
+                break;
+            }
+
+            current.content = grown;
+            node = current.parent;
+        }
     }
 
-    pub fn can_contain(&self, other: &Aabb3<S>) -> bool
-        where S: BaseFloat
-    {
+    /// Recompute the content bounds of this node and its ancestors after an item was
+    /// removed, if the bounds are kept tight.
+    fn shrink_content<H>(&mut self, ctx: &Context<S, K, H>) {
+        if ctx.tight_bounds {
+            self.refresh_content();
+        }
+    }
+
+    /// Recompute the content bounds of this node and its ancestors after an item was
+    /// removed or changed its volume.
+    fn refresh_content(&mut self) {
+        let mut node: *mut Node<S, K> = self;
+        while let Some(current) = unsafe { node.as_mut() } {
+            let content = current.compute_content();
+            if current.content == content {
+                break;
+            }
+
+            current.content = content;
+            node = current.parent;
+        }
+    }
+
+    /// Recompute the content bounds of the whole subtree.
+    pub fn tighten_content(&mut self) {
+        if self.is_branch {
+            for i in 0..8 {
+                self.get_node_mut(i).tighten_content();
+            }
+        }
+        self.set_content(self.compute_content());
+    }
+
+    pub fn compute_content(&self) -> Option<Aabb3<S>> {
+        let children = (0..8)
+            .filter(|_| self.is_branch)
+            .filter_map(|i| self.get_node(i).content);
+
+        self.leafs
+            .iter()
+            .map(|leaf| leaf.bb)
+            .chain(children)
+            .fold(None, |content, bb| Some(content.map_or(bb, |content| union(&content, &bb))))
+    }
+
+    pub fn can_contain(&self, other: &Aabb3<S>) -> bool {
         if self.parent.is_null() {
             return true;
         }
@@ -194,17 +757,13 @@ impl<S, K> Node<S, K>
         self.contains_bb(other)
     }
 
-    pub fn contains_bb(&self, other: &Aabb3<S>) -> bool
-        where S: BaseFloat
-    {
+    pub fn contains_bb(&self, other: &Aabb3<S>) -> bool {
         other.min.x >= self.bb.min.x && other.min.y >= self.bb.min.y &&
             other.min.z >= self.bb.min.z && other.max.x <= self.bb.max.x &&
             other.max.y <= self.bb.max.y && other.max.z <= self.bb.max.z
     }
 
-    fn find_new_parent(&mut self, bb: &Aabb3<S>) -> &mut Node<S, K>
-        where S: BaseFloat
-    {
+    fn find_new_parent(&mut self, bb: &Aabb3<S>) -> &mut Node<S, K> {
         assert!(!self.parent.is_null());
 
         // println!("parent candidate {:?}", self.parent);
@@ -218,31 +777,63 @@ impl<S, K> Node<S, K>
         node
     }
 
-    fn try_cleanup_parent(&mut self) {
+    fn try_cleanup_parent<H>(&mut self, ctx: &mut Context<S, K, H>)
+        where H: BuildHasher
+    {
         if !self.parent.is_null() {
-            unsafe { self.parent.as_mut() }.unwrap().downgrade_to_leaf_if_children_empty();
+            unsafe { self.parent.as_mut() }.unwrap().downgrade_to_leaf_if_children_empty(ctx);
         }
     }
 
-    pub fn downgrade_to_leaf_if_children_empty(&mut self) {
+    pub fn downgrade_to_leaf_if_children_empty<H>(&mut self, ctx: &mut Context<S, K, H>)
+        where H: BuildHasher
+    {
+        if !self.collapse_if_children_empty(ctx) {
+            return;
+        }
+
+        if self.leafs.is_empty() {
+            self.try_cleanup_parent(ctx);
+        }
+    }
+
+    /// Remove children if all of them are empty, returns `true` if the node was collapsed.
+    fn collapse_if_children_empty<H>(&mut self, ctx: &mut Context<S, K, H>) -> bool
+        where H: BuildHasher
+    {
         for i in 0..8 {
             if !self.get_node(i).is_empty() {
-                return;
+                return false;
             }
         }
 
-        self.clean_nodes();
+        if ctx.is_recording() {
+            let children = self.child_ids();
+            ctx.emit(Event::Collapsed {
+                node: self.id,
+                children: children,
+            });
+        }
+
+        for i in 0..8 {
+            let child = self.take_node(i);
+            ctx.release_node(child);
+        }
         self.is_branch = false;
 
-        if self.leafs.is_empty() {
-            self.try_cleanup_parent();
+        true
+    }
+
+    fn child_ids(&self) -> [NodeId; 8] {
+        let mut ids = [NodeId(0); 8];
+        for (i, id) in ids.iter_mut().enumerate() {
+            *id = self.get_node(i).id;
         }
+        ids
     }
 
-    fn move_leafs_to_children(&mut self,
-                              branch_size: usize,
-                              object_node: &mut HashMap<K, *mut Node<S, K>>)
-        where S: BaseFloat
+    fn move_leafs_to_children<H>(&mut self, ctx: &mut Context<S, K, H>)
+        where H: BuildHasher
     {
         // println!("move_leafs_to_children");
 
@@ -256,9 +847,10 @@ impl<S, K> Node<S, K>
             };
 
             if let Some(index) = node_index {
-                let item = self.leafs.swap_remove(i);
+                let item = self.leafs.remove(i);
+                self.touch();
 
-                self.get_node_mut(index).insert(branch_size, item.id, item.bb, object_node);
+                self.get_node_mut(index).insert(item.id, item.bb, ctx);
                 continue;
             }
 
@@ -266,7 +858,9 @@ impl<S, K> Node<S, K>
         }
     }
 
-    fn subdivide(&mut self) {
+    fn subdivide<H>(&mut self, ctx: &mut Context<S, K, H>)
+        where H: BuildHasher
+    {
         // println!("subdivide");
 
         let v = self.bb;
@@ -275,44 +869,58 @@ impl<S, K> Node<S, K>
         // 0 .. 4 ---------------------------- where z < center.z
         // 0 .. 2 ---------------- where y < center.y
         // 0 where x < center.x
-        self.set_node(0, node(v.min, c));
+        self.set_node(0, node(ctx, v.min, c));
 
         // 1 where x > center.x
         self.set_node(1,
-                      node(Point3::new(c.x, v.min.y, v.min.z),
+                      node(ctx,
+                           Point3::new(c.x, v.min.y, v.min.z),
                            Point3::new(v.max.x, c.y, c.z)));
 
         // 2 .. 4 ---------------- where y > center.y
         // 2 where x < center.x
         self.set_node(2,
-                      node(Point3::new(v.min.x, c.y, v.min.z),
+                      node(ctx,
+                           Point3::new(v.min.x, c.y, v.min.z),
                            Point3::new(c.x, v.max.y, c.z)));
 
         // 3 where x > center.x
         self.set_node(3,
-                      node(Point3::new(c.x, c.y, v.min.z),
+                      node(ctx,
+                           Point3::new(c.x, c.y, v.min.z),
                            Point3::new(v.max.x, v.max.y, c.z)));
 
         // 4 .. 8 ---------------------------- where z > center.z
         // 4 .. 6 ---------------- where y < center.y
         // 4 where x < center.x
         self.set_node(4,
-                      node(Point3::new(v.min.x, v.min.y, c.z),
+                      node(ctx,
+                           Point3::new(v.min.x, v.min.y, c.z),
                            Point3::new(c.x, c.y, v.max.z)));
 
         // 5 where x > center.x
         self.set_node(5,
-                      node(Point3::new(c.x, v.min.y, c.z),
+                      node(ctx,
+                           Point3::new(c.x, v.min.y, c.z),
                            Point3::new(v.max.x, c.y, v.max.z)));
 
         // 6 .. 8 ---------------- where y > center.y
         // 6 where x < center.x
         self.set_node(6,
-                      node(Point3::new(v.min.x, c.y, c.z),
+                      node(ctx,
+                           Point3::new(v.min.x, c.y, c.z),
                            Point3::new(c.x, v.max.y, v.max.z)));
 
         // 7 where x > center.x
-        self.set_node(7, node(c, v.max));
+        self.set_node(7, node(ctx, c, v.max));
+
+        if ctx.is_recording() {
+            let children = self.child_ids();
+            ctx.emit(Event::Subdivided {
+                node: self.id,
+                children: children,
+            });
+        }
     }
 
     /// Item must exist!
@@ -334,6 +942,7 @@ impl<S, K> Node<S, K>
         // forget the box
         node.parent = self;
         self.nodes[index] = Box::<Node<S, K>>::into_raw(node);
+        self.touch();
     }
 
     fn take_node(&mut self, index: usize) -> Box<Node<S, K>> {
@@ -345,6 +954,7 @@ impl<S, K> Node<S, K>
         let mut raw = unsafe { self.nodes.get_unchecked_mut(index) };
         let ret = unsafe { Box::<Node<S, K>>::from_raw(*raw) };
         *raw = ptr::null_mut();
+        self.touch();
         ret
     }
 
@@ -358,11 +968,52 @@ impl<S, K> Node<S, K>
         unsafe { self.nodes.get_unchecked(index).as_ref() }.unwrap()
     }
 
-    fn select_node_index(&self, bb: &Aabb3<S>) -> Option<usize>
-        where S: BaseFloat
-    {
+    pub fn get_parent(&self) -> Option<&Node<S, K>> {
+        unsafe { self.parent.as_ref() }
+    }
+
+    /// Find the index of this node in the parent's children.
+    pub fn octant(&self) -> Option<usize> {
+        let parent = self.get_parent()?;
+        (0..8).find(|&i| ptr::eq(parent.nodes[i], self))
+    }
+
+    /// Subtract `offset` from the volumes of this node, its children and all contained items.
+    ///
+    /// Subtracting the same offset preserves the ordering of coordinates, so items remain
+    /// inside the same nodes and no restructuring is needed.
+    pub fn shift_origin(&mut self, offset: Vector3<S>) {
+        self.bb = shift_bb(&self.bb, offset);
+        self.content = self.content.map(|content| shift_bb(&content, offset));
+        self.dirty = true;
+        self.center = shift_point(self.center, offset);
+
+        for leaf in &mut self.leafs {
+            leaf.bb = shift_bb(&leaf.bb, offset);
+        }
+
+        if self.is_branch {
+            for i in 0..8 {
+                self.get_node_mut(i).shift_origin(offset);
+            }
+        }
+    }
+
+    /// Returns `false` if the center coincides with an edge of the volume on any axis,
+    /// meaning that some of the children would have no extent on that axis.
+    fn can_subdivide(&self) -> bool {
+        self.bb.min.x < self.center.x && self.center.x < self.bb.max.x &&
+            self.bb.min.y < self.center.y && self.center.y < self.bb.max.y &&
+            self.bb.min.z < self.center.z && self.center.z < self.bb.max.z
+    }
+
+    fn select_node_index(&self, bb: &Aabb3<S>) -> Option<usize> {
         let mut index = 0;
 
+        if !self.can_subdivide() {
+            return None;
+        }
+
         if !self.contains_bb(bb) {
             // println!("{:?} can not contain {:?}", self.bb, bb);
             return None;
@@ -418,9 +1069,67 @@ impl<S, K> Drop for Node<S, K>
     }
 }
 
-fn node<S, K>(p1: Point3<S>, p2: Point3<S>) -> Box<Node<S, K>>
+/// Returns `true` if the volumes intersect or touch.
+fn overlaps<S>(a: &Aabb3<S>, b: &Aabb3<S>) -> bool
+    where S: BaseNum
+{
+    a.min.x <= b.max.x && a.max.x >= b.min.x && a.min.y <= b.max.y && a.max.y >= b.min.y &&
+        a.min.z <= b.max.z && a.max.z >= b.min.z
+}
+
+/// Part of `bb` that is inside the `bounds`.
+fn clip<S>(bb: &Aabb3<S>, bounds: &Aabb3<S>) -> Option<Aabb3<S>>
+    where S: BaseNum
+{
+    if !overlaps(bb, bounds) {
+        return None;
+    }
+
+    Some(Aabb3::new(Point3::new(bb.min.x.partial_max(bounds.min.x),
+                                bb.min.y.partial_max(bounds.min.y),
+                                bb.min.z.partial_max(bounds.min.z)),
+                    Point3::new(bb.max.x.partial_min(bounds.max.x),
+                                bb.max.y.partial_min(bounds.max.y),
+                                bb.max.z.partial_min(bounds.max.z))))
+}
+
+/// Smallest volume containing both `a` and `b`.
+fn union<S>(a: &Aabb3<S>, b: &Aabb3<S>) -> Aabb3<S>
+    where S: BaseNum
+{
+    Aabb3::new(Point3::new(a.min.x.partial_min(b.min.x),
+                           a.min.y.partial_min(b.min.y),
+                           a.min.z.partial_min(b.min.z)),
+               Point3::new(a.max.x.partial_max(b.max.x),
+                           a.max.y.partial_max(b.max.y),
+                           a.max.z.partial_max(b.max.z)))
+}
+
+fn node<S, K, H>(ctx: &mut Context<S, K, H>, p1: Point3<S>, p2: Point3<S>) -> Box<Node<S, K>>
     where S: BaseNum,
           K: Clone + Eq + Hash
 {
-    Box::new(Node::new(Aabb3::new(p1, p2)))
+    ctx.alloc_node(Aabb3::new(p1, p2))
+}
+
+/// Split point of `bb`, computed from the extent so that it does not overflow for integers.
+fn midpoint<S>(bb: &Aabb3<S>) -> Point3<S>
+    where S: BaseNum
+{
+    bb.min + (bb.max - bb.min) / (S::one() + S::one())
+}
+
+fn shift_bb<S>(bb: &Aabb3<S>, offset: Vector3<S>) -> Aabb3<S>
+    where S: BaseNum
+{
+    Aabb3 {
+        min: shift_point(bb.min, offset),
+        max: shift_point(bb.max, offset),
+    }
+}
+
+fn shift_point<S>(p: Point3<S>, offset: Vector3<S>) -> Point3<S>
+    where S: BaseNum
+{
+    Point3::new(p.x - offset.x, p.y - offset.y, p.z - offset.z)
 }
@@ -0,0 +1,246 @@
+use std::hash::Hash;
+use prelude::*;
+use cgmath::*;
+use collision::*;
+use Item;
+use event::NodeId;
+use node::Node;
+
+/// Read-only cursor to a tree node.
+///
+/// Allows walking the tree structure in both directions, see `Octree::root_node`.
+pub struct NodeRef<'a, S: 'a, K: 'a>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    node: &'a Node<S, K>,
+    depth: usize,
+}
+
+impl<'a, S: 'a, K: 'a> Clone for NodeRef<'a, S, K>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    fn clone(&self) -> NodeRef<'a, S, K> {
+        *self
+    }
+}
+
+impl<'a, S: 'a, K: 'a> Copy for NodeRef<'a, S, K>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{}
+
+impl<'a, S: 'a, K: 'a> NodeRef<'a, S, K>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    pub fn new<'r>(node: &'r Node<S, K>, depth: usize) -> NodeRef<'r, S, K> {
+        NodeRef {
+            node: node,
+            depth: depth,
+        }
+    }
+
+    /// Identifier that stays the same while the node exists.
+    pub fn id(&self) -> NodeId {
+        self.node.id
+    }
+
+    /// Volume of space covered by this node.
+    pub fn bounds(&self) -> Aabb3<S> {
+        self.node.bb
+    }
+
+    /// Union of the item volumes in the subtree of this node, `None` if it has no items.
+    ///
+    /// Items that cross the node boundary are included whole, so this can extend beyond
+    /// `bounds`. The volume can be larger than the items if tight bounds are disabled, see
+    /// `Octree::set_tight_bounds`.
+    pub fn content_bounds(&self) -> Option<Aabb3<S>> {
+        self.node.content
+    }
+
+    /// Distance from the root node, which has depth `0`.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Index of this node in the parent's children, `None` for the root node.
+    ///
+    /// Octants are numbered by adding `1` if the node is above the parent's center on `x`
+    /// axis, `2` for `y` axis, and `4` for `z` axis.
+    pub fn octant(&self) -> Option<usize> {
+        self.node.octant()
+    }
+
+    /// Returns `true` if this node is subdivided into 8 children.
+    pub fn is_branch(&self) -> bool {
+        self.node.is_branch
+    }
+
+    /// Items stored directly in this node.
+    ///
+    /// In branch nodes these are the items that cross the boundaries of children.
+    pub fn items(&self) -> &'a [Item<S, K>] {
+        &self.node.leafs
+    }
+
+    /// Parent node, `None` for the root node.
+    pub fn parent(&self) -> Option<NodeRef<'a, S, K>> {
+        self.node.get_parent().map(|parent| NodeRef::new(parent, self.depth - 1))
+    }
+
+    /// Child node at `octant` index, `None` if this node is not a branch.
+    pub fn child(&self, octant: usize) -> Option<NodeRef<'a, S, K>> {
+        if !self.node.is_branch {
+            return None;
+        }
+
+        Some(NodeRef::new(self.node.get_node(octant), self.depth + 1))
+    }
+
+    /// Leaf nodes adjacent to the `face` of this node, empty at the world boundary.
+    ///
+    /// If the neighbouring region is subdivided deeper than this node, all leaf nodes
+    /// touching the face are returned in octant order. If it is subdivided less, the
+    /// single larger leaf node is returned.
+    pub fn neighbors(&self, face: Face) -> Vec<NodeRef<'a, S, K>> {
+        let bit = face.octant_bit();
+        let positive = face.is_positive();
+
+        // climb until the node is on the side of its parent that faces the neighbour,
+        // remembering the path to mirror it on the way down
+        let mut path = Vec::new();
+        let mut node = *self;
+        let mut neighbor = loop {
+            let (parent, octant) = match (node.parent(), node.octant()) {
+                (Some(parent), Some(octant)) => (parent, octant),
+                _ => return Vec::new(),
+            };
+
+            if on_side(octant, bit, !positive) {
+                break parent.child(octant ^ bit).unwrap();
+            }
+
+            path.push(octant);
+            node = parent;
+        };
+
+        while let Some(octant) = path.pop() {
+            match neighbor.child(octant ^ bit) {
+                Some(child) => neighbor = child,
+                None => break,
+            }
+        }
+
+        let mut result = Vec::new();
+        collect_facing_leafs(neighbor, bit, positive, &mut result);
+        result
+    }
+
+    /// Iterator over all 8 children in octant order, empty if this node is not a branch.
+    pub fn children(&self) -> Children<'a, S, K> {
+        Children {
+            parent: *self,
+            index: 0,
+        }
+    }
+}
+
+/// Face of a node volume, see `NodeRef::neighbors`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Face {
+    MinX,
+    MaxX,
+    MinY,
+    MaxY,
+    MinZ,
+    MaxZ,
+}
+
+impl Face {
+    /// All faces in axis order.
+    pub fn all() -> [Face; 6] {
+        [Face::MinX, Face::MaxX, Face::MinY, Face::MaxY, Face::MinZ, Face::MaxZ]
+    }
+
+    /// Face on the opposite side of the volume.
+    pub fn opposite(self) -> Face {
+        match self {
+            Face::MinX => Face::MaxX,
+            Face::MaxX => Face::MinX,
+            Face::MinY => Face::MaxY,
+            Face::MaxY => Face::MinY,
+            Face::MinZ => Face::MaxZ,
+            Face::MaxZ => Face::MinZ,
+        }
+    }
+
+    fn octant_bit(self) -> usize {
+        match self {
+            Face::MinX | Face::MaxX => 1,
+            Face::MinY | Face::MaxY => 2,
+            Face::MinZ | Face::MaxZ => 4,
+        }
+    }
+
+    fn is_positive(self) -> bool {
+        match self {
+            Face::MaxX | Face::MaxY | Face::MaxZ => true,
+            Face::MinX | Face::MinY | Face::MinZ => false,
+        }
+    }
+}
+
+/// Returns `true` if the child at `octant` lies on the positive side of the `bit` axis, or
+/// on the negative side if `positive` is `false`.
+fn on_side(octant: usize, bit: usize, positive: bool) -> bool {
+    (octant & bit != 0) == positive
+}
+
+/// Collect leafs of `node` subtree that face back against the `positive` direction.
+fn collect_facing_leafs<'a, S, K>(node: NodeRef<'a, S, K>,
+                                  bit: usize,
+                                  positive: bool,
+                                  result: &mut Vec<NodeRef<'a, S, K>>)
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    if !node.is_branch() {
+        result.push(node);
+        return;
+    }
+
+    for (octant, child) in node.children().enumerate() {
+        if on_side(octant, bit, !positive) {
+            collect_facing_leafs(child, bit, positive, result);
+        }
+    }
+}
+
+/// Iterator over node children.
+pub struct Children<'a, S: 'a, K: 'a>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    parent: NodeRef<'a, S, K>,
+    index: usize,
+}
+
+impl<'a, S: 'a, K: 'a> Iterator for Children<'a, S, K>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    type Item = NodeRef<'a, S, K>;
+
+    fn next(&mut self) -> Option<NodeRef<'a, S, K>> {
+        if self.index >= 8 {
+            return None;
+        }
+
+        let child = self.parent.child(self.index);
+        self.index += 1;
+        child
+    }
+}
@@ -0,0 +1,459 @@
+//! Point cloud import and statistics.
+//!
+//! Points are read from ASCII XYZ or PLY files and indexed with `index`, which stores every
+//! point as an item with a zero-sized volume at the point, keyed by its position in the
+//! input. The other functions take such a tree: `density` reports the number of points per
+//! node volume, `remove_outliers` drops isolated points and `voxel_downsample` replaces the
+//! points in every cell of a grid by their centroid.
+
+use std::cmp;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, BuildHasher};
+use std::io::{self, BufRead};
+use cgmath::*;
+use collision::*;
+use {Octree, NodeRef, Scalar, Error, Result};
+
+/// Largest number of points allocated up front for a PLY vertex element.
+const MAX_PREALLOCATED_POINTS: usize = 1 << 20;
+
+/// Read points from an ASCII XYZ file.
+///
+/// Every line holds the `x`, `y` and `z` coordinates separated by whitespace or commas.
+/// Further columns, such as colour or intensity, are ignored, as are empty lines and lines
+/// starting with `#` or `//`.
+pub fn read_xyz<R>(input: R) -> io::Result<Vec<Point3<f64>>>
+    where R: BufRead
+{
+    let mut points = Vec::new();
+
+    for (number, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+            continue;
+        }
+
+        let mut coords = [0.0; 3];
+        let mut fields = line.split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|field| !field.is_empty());
+        for coord in &mut coords {
+            *coord = match fields.next().map(|field| field.parse()) {
+                Some(Ok(value)) => value,
+                Some(Err(_)) => return invalid(format!("line {}: invalid coordinate", number + 1)),
+                None => return invalid(format!("line {}: missing coordinate", number + 1)),
+            };
+        }
+        points.push(Point3::new(coords[0], coords[1], coords[2]));
+    }
+
+    Ok(points)
+}
+
+/// Read the `x`, `y` and `z` properties of the `vertex` element of a PLY file.
+///
+/// Binary little and big endian files are read, as well as ASCII ones. The properties can
+/// have any scalar type, and other properties and elements are skipped.
+pub fn read_ply<R>(mut input: R) -> io::Result<Vec<Point3<f64>>>
+    where R: BufRead
+{
+    let (format, elements) = read_ply_header(&mut input)?;
+    let mut values = PlyValues {
+        input: input,
+        format: format,
+        tokens: Vec::new(),
+    };
+
+    for element in &elements {
+        let coord_index = |name: &str| element.properties.iter().position(|p| p.name == name);
+        let coords = if element.name == "vertex" {
+            match (coord_index("x"), coord_index("y"), coord_index("z")) {
+                (Some(x), Some(y), Some(z)) => Some([x, y, z]),
+                _ => return invalid("vertex element has no x, y or z property".into()),
+            }
+        } else {
+            None
+        };
+
+        // the count comes from the header, so it is not trusted beyond a sane preallocation
+        let capacity = if coords.is_some() { element.count } else { 0 };
+        let mut points = Vec::with_capacity(cmp::min(capacity, MAX_PREALLOCATED_POINTS));
+        let mut row = vec![0.0; element.properties.len()];
+        for _ in 0..element.count {
+            for (value, property) in row.iter_mut().zip(&element.properties) {
+                match property.list {
+                    Some(count_type) => {
+                        let count = values.read(count_type)?;
+                        for _ in 0..count as usize {
+                            values.read(property.value)?;
+                        }
+                    }
+                    None => *value = values.read(property.value)?,
+                }
+            }
+
+            if let Some([x, y, z]) = coords {
+                points.push(Point3::new(row[x], row[y], row[z]));
+            }
+        }
+
+        // elements after the vertices are not needed
+        if coords.is_some() {
+            return Ok(points);
+        }
+    }
+
+    invalid("no vertex element".into())
+}
+
+/// Index `points` in a new tree, using the position of every point as its id.
+///
+/// The world volume of the tree is the bounding box of the points, extended to a unit size
+/// along any axis where all points have the same coordinate. Ids are looked up densely,
+/// see `Octree::with_dense_keys`.
+///
+/// Returns an error if there are no points, `branch_size` is zero or a point has
+/// non-finite coordinates.
+pub fn index<S>(points: &[Point3<S>], branch_size: usize) -> Result<Octree<S, usize>>
+    where S: Scalar + BaseFloat
+{
+    let mut bb = match points.first() {
+        Some(&point) => Aabb3::new(point, point),
+        None => return Err(Error::EmptyWorld),
+    };
+    for &point in points {
+        bb = bb.grow(point);
+    }
+    for axis in 0..3 {
+        if bb.max[axis] <= bb.min[axis] {
+            bb.max[axis] = bb.min[axis] + S::one();
+        }
+    }
+
+    let mut tree = Octree::try_with_dense_keys(branch_size, bb)?;
+    // a subdivision takes 8 nodes and is needed about once per branch full of points
+    tree.reserve_nodes(points.len() / branch_size * 8);
+    for (id, &point) in points.iter().enumerate() {
+        tree.try_update(id, Aabb3::new(point, point))?;
+    }
+
+    Ok(tree)
+}
+
+/// Number of points in a node subtree, see `density`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NodeDensity<S>
+    where S: BaseNum
+{
+    /// Volume of the node.
+    pub bounds: Aabb3<S>,
+    /// Depth of the node, the root node is at depth `0`.
+    pub depth: usize,
+    /// Number of points stored in the node and its descendants.
+    pub points: usize,
+    /// Number of points per unit of node volume.
+    pub density: S,
+}
+
+/// Get the number of points in every node of `tree` and its density, in the tree iteration
+/// order.
+///
+/// Points stored in several nodes with `StraddleMode::Children` are counted once per
+/// subtree.
+pub fn density<S, K, H>(tree: &Octree<S, K, H>) -> Vec<NodeDensity<S>>
+    where S: Scalar + BaseFloat,
+          K: Clone + Eq + Hash,
+          H: BuildHasher
+{
+    let mut result = Vec::new();
+    node_density(tree.root_node(), &mut result);
+    result
+}
+
+fn node_density<'a, S, K>(node: NodeRef<'a, S, K>,
+                          result: &mut Vec<NodeDensity<S>>)
+                          -> HashSet<&'a K>
+    where S: Scalar + BaseFloat,
+          K: Clone + Eq + Hash
+{
+    let bounds = node.bounds();
+    let index = result.len();
+    result.push(NodeDensity {
+        bounds: bounds,
+        depth: node.depth(),
+        points: 0,
+        density: S::zero(),
+    });
+
+    let mut ids: HashSet<&'a K> = node.items().iter().map(|item| &item.id).collect();
+    for child in node.children() {
+        ids.extend(node_density(child, result));
+    }
+
+    let size = bounds.max - bounds.min;
+    result[index].points = ids.len();
+    result[index].density = S::from(ids.len()).unwrap() / (size.x * size.y * size.z);
+
+    ids
+}
+
+/// Remove the points of `tree` that have fewer than `min_neighbours` other points within
+/// `radius`, and return their ids.
+///
+/// Neighbours are counted before any point is removed, so the result does not depend on
+/// the order of points.
+pub fn remove_outliers<S, K, H>(tree: &mut Octree<S, K, H>,
+                                radius: S,
+                                min_neighbours: usize)
+                                -> Vec<K>
+    where S: Scalar + BaseFloat,
+          K: Clone + Eq + Hash + fmt::Debug,
+          H: BuildHasher
+{
+    let outliers: Vec<K> = tree.into_iter()
+        .filter(|point| {
+            let around = Sphere {
+                center: point.bb.min,
+                radius: radius,
+            };
+            let neighbours = tree.overlapping(around)
+                .filter(|other| other.id != point.id)
+                .take(min_neighbours)
+                .count();
+            neighbours < min_neighbours
+        })
+        .map(|point| point.id.clone())
+        .collect();
+
+    for id in &outliers {
+        tree.remove(id.clone());
+    }
+
+    outliers
+}
+
+/// Get the centroid of the points of `tree` in every non-empty cell of a grid with cells
+/// of `voxel_size`, aligned to the origin.
+///
+/// Centroids are ordered by cell, with the `x` index changing fastest.
+///
+/// Returns an error if `voxel_size` is not finite and greater than zero, or a point is so
+/// far from the origin that its cell index does not fit into an `i64`.
+pub fn voxel_downsample<S, K, H>(tree: &Octree<S, K, H>, voxel_size: S) -> Result<Vec<Point3<S>>>
+    where S: Scalar + BaseFloat,
+          K: Clone + Eq + Hash + fmt::Debug,
+          H: BuildHasher
+{
+    if !voxel_size.is_finite() || voxel_size <= S::zero() {
+        return Err(Error::InvalidVoxelSize);
+    }
+
+    let cell = |coord: S| (coord / voxel_size).floor().to_i64().ok_or(Error::CoordinateOutOfRange);
+    let mut cells = BTreeMap::new();
+    for point in tree {
+        let point = point.bb.min;
+        let key = (cell(point.z)?, cell(point.y)?, cell(point.x)?);
+        let sum = cells.entry(key).or_insert((Vector3::zero(), 0));
+        sum.0 += point.to_vec();
+        sum.1 += 1;
+    }
+
+    Ok(cells.values()
+        .map(|&(sum, count)| Point3::from_vec(sum / S::from(count).unwrap()))
+        .collect())
+}
+
+fn invalid<T>(message: String) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PlyFormat {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PlyType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl PlyType {
+    fn parse(name: &str) -> io::Result<PlyType> {
+        Ok(match name {
+            "char" | "int8" => PlyType::I8,
+            "uchar" | "uint8" => PlyType::U8,
+            "short" | "int16" => PlyType::I16,
+            "ushort" | "uint16" => PlyType::U16,
+            "int" | "int32" => PlyType::I32,
+            "uint" | "uint32" => PlyType::U32,
+            "float" | "float32" => PlyType::F32,
+            "double" | "float64" => PlyType::F64,
+            _ => return invalid(format!("unknown property type {}", name)),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            PlyType::I8 | PlyType::U8 => 1,
+            PlyType::I16 | PlyType::U16 => 2,
+            PlyType::I32 | PlyType::U32 | PlyType::F32 => 4,
+            PlyType::F64 => 8,
+        }
+    }
+}
+
+struct PlyProperty {
+    name: String,
+    value: PlyType,
+    /// Type of the element count of list properties.
+    list: Option<PlyType>,
+}
+
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<PlyProperty>,
+}
+
+fn read_ply_header<R>(input: &mut R) -> io::Result<(PlyFormat, Vec<PlyElement>)>
+    where R: BufRead
+{
+    let mut format = None;
+    let mut elements: Vec<PlyElement> = Vec::new();
+
+    let mut line = String::new();
+    for number in 1.. {
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return invalid("missing end_header".into());
+        }
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if number == 1 {
+            if words != ["ply"] {
+                return invalid("not a PLY file".into());
+            }
+            continue;
+        }
+
+        match words.first().cloned() {
+            Some("format") if words.len() == 3 => {
+                format = Some(match words[1] {
+                    "ascii" => PlyFormat::Ascii,
+                    "binary_little_endian" => PlyFormat::LittleEndian,
+                    "binary_big_endian" => PlyFormat::BigEndian,
+                    other => return invalid(format!("unknown format {}", other)),
+                });
+            }
+            Some("element") if words.len() == 3 => {
+                elements.push(PlyElement {
+                    name: words[1].into(),
+                    count: match words[2].parse() {
+                        Ok(count) => count,
+                        Err(_) => return invalid(format!("line {}: invalid count", number)),
+                    },
+                    properties: Vec::new(),
+                });
+            }
+            Some("property") => {
+                let property = match words.len() {
+                    3 => {
+                        PlyProperty {
+                            name: words[2].into(),
+                            value: PlyType::parse(words[1])?,
+                            list: None,
+                        }
+                    }
+                    5 if words[1] == "list" => {
+                        PlyProperty {
+                            name: words[4].into(),
+                            value: PlyType::parse(words[3])?,
+                            list: Some(PlyType::parse(words[2])?),
+                        }
+                    }
+                    _ => return invalid(format!("line {}: invalid property", number)),
+                };
+                match elements.last_mut() {
+                    Some(element) => element.properties.push(property),
+                    None => return invalid(format!("line {}: property before element", number)),
+                }
+            }
+            Some("end_header") => break,
+            Some("comment") | Some("obj_info") | None => {}
+            Some(_) => return invalid(format!("line {}: unknown header entry", number)),
+        }
+    }
+
+    match format {
+        Some(format) => Ok((format, elements)),
+        None => invalid("missing format".into()),
+    }
+}
+
+/// Reader of the property values in the body of a PLY file.
+struct PlyValues<R> {
+    input: R,
+    format: PlyFormat,
+    /// Remaining values of the current ASCII line, in reverse order.
+    tokens: Vec<String>,
+}
+
+impl<R> PlyValues<R>
+    where R: BufRead
+{
+    fn read(&mut self, value: PlyType) -> io::Result<f64> {
+        if self.format == PlyFormat::Ascii {
+            return self.read_ascii();
+        }
+
+        let mut bytes = [0; 8];
+        let bytes = &mut bytes[..value.size()];
+        self.input.read_exact(bytes)?;
+        if self.format == PlyFormat::LittleEndian {
+            bytes.reverse();
+        }
+
+        // big endian from here on
+        let mut bits = 0u64;
+        for &byte in bytes.iter() {
+            bits = bits << 8 | byte as u64;
+        }
+
+        Ok(match value {
+            PlyType::I8 => bits as u8 as i8 as f64,
+            PlyType::U8 => bits as u8 as f64,
+            PlyType::I16 => bits as u16 as i16 as f64,
+            PlyType::U16 => bits as u16 as f64,
+            PlyType::I32 => bits as u32 as i32 as f64,
+            PlyType::U32 => bits as u32 as f64,
+            PlyType::F32 => f32::from_bits(bits as u32) as f64,
+            PlyType::F64 => f64::from_bits(bits),
+        })
+    }
+
+    fn read_ascii(&mut self) -> io::Result<f64> {
+        let mut line = String::new();
+        while self.tokens.is_empty() {
+            line.clear();
+            if self.input.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "missing values"));
+            }
+            self.tokens = line.split_whitespace().rev().map(String::from).collect();
+        }
+
+        match self.tokens.pop().unwrap().parse() {
+            Ok(value) => Ok(value),
+            Err(_) => invalid("invalid value".into()),
+        }
+    }
+}
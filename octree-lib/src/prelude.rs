@@ -0,0 +1,4 @@
+//! Allocating types, which are not part of the prelude in `no_std` builds.
+
+pub use alloc::boxed::Box;
+pub use alloc::vec::Vec;
@@ -0,0 +1,119 @@
+use std::cmp::Ordering;
+use std::hash::Hash;
+use cgmath::*;
+use Item;
+use node::Node;
+use super::{closest_point, distance2};
+
+/// Item closest to the query point.
+pub struct ClosestItem<'a, S: 'a, K: 'a> {
+    item: &'a Item<S, K>,
+    point: Point3<S>,
+    distance: S,
+}
+
+impl<'a, S: 'a, K: 'a> ClosestItem<'a, S, K>
+    where S: Clone
+{
+    /// Retrieve the reference to item.
+    pub fn item(&self) -> &'a Item<S, K> {
+        self.item
+    }
+
+    /// Retrieve the point on the item volume closest to the query point.
+    pub fn point(&self) -> Point3<S> {
+        self.point.clone()
+    }
+
+    /// Retrieve the distance from the query point, zero if the point is inside the item.
+    pub fn distance(&self) -> S {
+        self.distance.clone()
+    }
+}
+
+/// Find the item closest to `point`, ignoring items further than `sqrt(max_distance2)`.
+///
+/// Children are searched nearest first, and nodes further than the best item found so far
+/// are skipped.
+pub fn find<'a, S, K>(root: &'a Node<S, K>,
+                      point: Point3<S>,
+                      max_distance2: Option<S>)
+                      -> Option<ClosestItem<'a, S, K>>
+    where S: BaseFloat,
+          K: Clone + Eq + Hash
+{
+    let mut best = None;
+    let mut limit = max_distance2;
+
+    search(root, point, &mut best, &mut limit);
+
+    best.map(|(item, distance2): (&'a Item<S, K>, S)| {
+        ClosestItem {
+            item: item,
+            point: closest_point(&item.bb, point),
+            distance: distance2.sqrt(),
+        }
+    })
+}
+
+/// Returns `true` if the search can be stopped because the point is inside an item.
+fn search<'a, S, K>(node: &'a Node<S, K>,
+                    point: Point3<S>,
+                    best: &mut Option<(&'a Item<S, K>, S)>,
+                    limit: &mut Option<S>)
+                    -> bool
+    where S: BaseFloat,
+          K: Clone + Eq + Hash
+{
+    for leaf in &node.leafs {
+        let d = distance2(&leaf.bb, point);
+        let is_better = match (*best, *limit) {
+            (Some((_, best_d)), _) => d < best_d,
+            (None, Some(limit)) => d <= limit,
+            (None, None) => true,
+        };
+
+        if is_better {
+            *best = Some((leaf, d));
+            *limit = Some(d);
+
+            if d == S::zero() {
+                return true;
+            }
+        }
+    }
+
+    if !node.is_branch {
+        return false;
+    }
+
+    let mut children = [(S::zero(), 0); 8];
+    for (i, child) in children.iter_mut().enumerate() {
+        // children without items are sorted last and skipped
+        let d = match node.get_node(i).content {
+            Some(ref content) => distance2(content, point),
+            None => S::infinity(),
+        };
+        *child = (d, i);
+    }
+    children.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+
+    for &(d, i) in &children {
+        if let Some(limit) = *limit {
+            if d > limit {
+                break;
+            }
+        }
+
+        let child = node.get_node(i);
+        if child.content.is_none() {
+            continue;
+        }
+
+        if search(child, point, best, limit) {
+            return true;
+        }
+    }
+
+    false
+}
@@ -0,0 +1,80 @@
+use cgmath::*;
+use collision::*;
+
+pub mod closest;
+pub mod ray_cast;
+pub mod ray_packet;
+pub mod tracker;
+pub mod visibility;
+
+/// Point inside or on the surface of `bb` that is closest to `point`.
+pub fn closest_point<S>(bb: &Aabb3<S>, point: Point3<S>) -> Point3<S>
+    where S: BaseNum
+{
+    Point3::new(point.x.partial_max(bb.min.x).partial_min(bb.max.x),
+                point.y.partial_max(bb.min.y).partial_min(bb.max.y),
+                point.z.partial_max(bb.min.z).partial_min(bb.max.z))
+}
+
+/// Returns `true` if `inner` is inside or on the surface of `outer`.
+pub fn contains_bb<S>(outer: &Aabb3<S>, inner: &Aabb3<S>) -> bool
+    where S: BaseNum
+{
+    contains_point(outer, inner.min) && contains_point(outer, inner.max)
+}
+
+/// Returns `true` if `point` is inside or on the surface of `bb`.
+pub fn contains_point<S>(bb: &Aabb3<S>, point: Point3<S>) -> bool
+    where S: BaseNum
+{
+    bb.min.x <= point.x && point.x <= bb.max.x && bb.min.y <= point.y &&
+    point.y <= bb.max.y && bb.min.z <= point.z && point.z <= bb.max.z
+}
+
+/// Squared distance from `point` to the closest point of `bb`, zero if the point is inside.
+pub fn distance2<S>(bb: &Aabb3<S>, point: Point3<S>) -> S
+    where S: BaseNum
+{
+    let closest = closest_point(bb, point);
+    let d = closest - point;
+    d.x * d.x + d.y * d.y + d.z * d.z
+}
+
+/// Ray parameter at which `ray` enters `bb`, zero if the origin is inside.
+///
+/// Unlike the `collision` ray test, axes where the ray direction is zero are checked too.
+pub fn ray_entry<S>(ray: &Ray3<S>, bb: &Aabb3<S>) -> Option<S>
+    where S: BaseFloat
+{
+    let mut t_min = S::zero();
+    let mut t_max = S::infinity();
+
+    for i in 0..3 {
+        if ray.direction[i] == S::zero() {
+            if ray.origin[i] < bb.min[i] || ray.origin[i] > bb.max[i] {
+                return None;
+            }
+            continue;
+        }
+
+        let t1 = (bb.min[i] - ray.origin[i]) / ray.direction[i];
+        let t2 = (bb.max[i] - ray.origin[i]) / ray.direction[i];
+        t_min = t_min.max(t1.min(t2));
+        t_max = t_max.min(t1.max(t2));
+        if t_min > t_max {
+            return None;
+        }
+    }
+
+    Some(t_min)
+}
+
+/// Returns `true` if ray parameter `t` is before the `best` hit found so far.
+pub fn is_closer<S, T>(t: S, best: Option<(T, S)>) -> bool
+    where S: BaseFloat
+{
+    match best {
+        Some((_, best_t)) => t < best_t,
+        None => true,
+    }
+}
@@ -0,0 +1,75 @@
+use std::cmp::Ordering;
+use std::hash::Hash;
+use prelude::*;
+use cgmath::*;
+use collision::*;
+use Item;
+use RayIntersection;
+use node::Node;
+use super::{ray_entry, is_closer};
+
+/// Find the nearest item for which `hit` returns a distance along `ray`.
+///
+/// `hit` is called only for items whose volume the ray enters before the best distance
+/// found so far, and children are searched nearest first, stopping at the first one that
+/// the ray enters beyond that distance.
+pub fn nearest<'a, S, K, F>(root: &'a Node<S, K>,
+                            ray: Ray3<S>,
+                            mut hit: F)
+                            -> Option<RayIntersection<'a, S, K>>
+    where S: BaseFloat,
+          K: Clone + Eq + Hash,
+          F: FnMut(&Item<S, K>, &Ray3<S>) -> Option<S>
+{
+    let mut best = None;
+
+    // the root is searched unconditionally, it keeps items outside of the world
+    search(root, &ray, &mut hit, &mut best);
+
+    best.map(|(item, t): (&'a Item<S, K>, S)| {
+        RayIntersection::new(ray.origin + ray.direction * t, item)
+    })
+}
+
+fn search<'a, S, K, F>(node: &'a Node<S, K>,
+                       ray: &Ray3<S>,
+                       hit: &mut F,
+                       best: &mut Option<(&'a Item<S, K>, S)>)
+    where S: BaseFloat,
+          K: Clone + Eq + Hash,
+          F: FnMut(&Item<S, K>, &Ray3<S>) -> Option<S>
+{
+    for leaf in &node.leafs {
+        match ray_entry(ray, &leaf.bb) {
+            Some(t) if is_closer(t, *best) => {}
+            _ => continue,
+        }
+
+        if let Some(t) = hit(leaf, ray) {
+            if is_closer(t, *best) {
+                *best = Some((leaf, t));
+            }
+        }
+    }
+
+    if !node.is_branch {
+        return;
+    }
+
+    let mut children = Vec::with_capacity(8);
+    for i in 0..8 {
+        let child = node.get_node(i);
+        let entry = child.content.and_then(|content| ray_entry(ray, &content));
+        if let Some(t) = entry {
+            children.push((t, child));
+        }
+    }
+    children.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+
+    for &(t, child) in &children {
+        if !is_closer(t, *best) {
+            break;
+        }
+        search(child, ray, hit, best);
+    }
+}
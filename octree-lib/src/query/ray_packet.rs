@@ -0,0 +1,89 @@
+use std::cmp::Ordering;
+use std::hash::Hash;
+use prelude::*;
+use cgmath::*;
+use collision::*;
+use Item;
+use RayIntersection;
+use node::Node;
+use super::{ray_entry, is_closer};
+
+/// Find the nearest item hit by each of the `rays`, traversing the tree once.
+///
+/// Every node is tested only against the rays that hit its parent and have not found a
+/// closer item yet, and the node is skipped when that subset becomes empty.
+pub fn nearest<'a, S, K>(root: &'a Node<S, K>,
+                         rays: &[Ray3<S>])
+                         -> Vec<Option<RayIntersection<'a, S, K>>>
+    where S: BaseFloat,
+          K: Clone + Eq + Hash
+{
+    let mut best = vec![None; rays.len()];
+    let active: Vec<usize> = (0..rays.len()).collect();
+
+    // the root is searched unconditionally, it keeps items outside of the world
+    search(root, rays, &active, &mut best);
+
+    best.into_iter()
+        .zip(rays)
+        .map(|(hit, ray)| {
+            hit.map(|(item, t): (&'a Item<S, K>, S)| {
+                RayIntersection::new(ray.origin + ray.direction * t, item)
+            })
+        })
+        .collect()
+}
+
+fn search<'a, S, K>(node: &'a Node<S, K>,
+                    rays: &[Ray3<S>],
+                    active: &[usize],
+                    best: &mut [Option<(&'a Item<S, K>, S)>])
+    where S: BaseFloat,
+          K: Clone + Eq + Hash
+{
+    for leaf in &node.leafs {
+        for &i in active {
+            if let Some(t) = ray_entry(&rays[i], &leaf.bb) {
+                if is_closer(t, best[i]) {
+                    best[i] = Some((leaf, t));
+                }
+            }
+        }
+    }
+
+    if !node.is_branch {
+        return;
+    }
+
+    let mut children = Vec::with_capacity(8);
+    for i in 0..8 {
+        let child = node.get_node(i);
+        let content = match child.content {
+            Some(content) => content,
+            None => continue,
+        };
+
+        if let Some(nearest) = active.iter()
+            .filter_map(|&r| ray_entry(&rays[r], &content))
+            .fold(None, |acc: Option<S>, t| Some(acc.map_or(t, |acc| acc.min(t)))) {
+            children.push((nearest, child));
+        }
+    }
+    children.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+
+    for &(_, child) in &children {
+        // filtered just before descending, so hits found in closer children prune this one
+        let content = child.content.unwrap();
+        let child_active: Vec<usize> = active.iter()
+            .cloned()
+            .filter(|&i| match ray_entry(&rays[i], &content) {
+                Some(t) => is_closer(t, best[i]),
+                None => false,
+            })
+            .collect();
+
+        if !child_active.is_empty() {
+            search(child, rays, &child_active, best);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use collections::HashSet;
+use std::hash::{Hash, BuildHasher};
+use std::ptr;
+use prelude::*;
+use cgmath::*;
+use collision::*;
+use {Octree, Overlap, OverlapIter, StraddleMode};
+use node::Node;
+
+/// Items that started or stopped overlapping the tracked volume, see `OverlapTracker`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OverlapChanges<K> {
+    /// Items that overlap the volume now, but did not before.
+    pub entered: Vec<K>,
+    /// Items that overlapped the volume before, but do not now.
+    pub exited: Vec<K>,
+}
+
+impl<K> OverlapChanges<K> {
+    /// Returns `true` if no item entered or exited the volume.
+    pub fn is_empty(&self) -> bool {
+        self.entered.is_empty() && self.exited.is_empty()
+    }
+}
+
+/// Overlap query that is repeated every frame with a slightly different volume, such as a
+/// camera frustum or a trigger volume.
+///
+/// Every `update` runs the overlap query again and compares the items found with the
+/// previous result to report enter and exit events; no node results are cached. The only
+/// reuse is the smallest node that contained the previous volume: while the tree revision is
+/// unchanged, the search for the next volume starts from there instead of the root node,
+/// walking up only as far as needed. Any modification of the tree, even far away from the
+/// volume, makes the next search start from the root node again.
+pub struct OverlapTracker<S, K>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    revision: Option<usize>,
+    anchor: *const Node<S, K>,
+    items: Vec<K>,
+}
+
+impl<S, K> OverlapTracker<S, K>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    pub fn new() -> OverlapTracker<S, K> {
+        OverlapTracker {
+            revision: None,
+            anchor: ptr::null(),
+            items: Vec::new(),
+        }
+    }
+
+    /// Items overlapping the volume of the last `update`, in the tree iteration order.
+    pub fn items(&self) -> &[K] {
+        &self.items
+    }
+
+    /// Forget the previous result, so that the next `update` reports all items as entered.
+    pub fn reset(&mut self) {
+        self.revision = None;
+        self.anchor = ptr::null();
+        self.items.clear();
+    }
+
+    /// Find the items of `tree` that overlap `volume`, and report the changes since the
+    /// last update.
+    ///
+    /// The search starts below the root node only if `volume` has a bounding box, and the
+    /// tree is the same and was not modified since the last update, see `Octree::revision`.
+    pub fn update<V, H>(&mut self, tree: &Octree<S, K, H>, volume: V) -> OverlapChanges<K>
+        where V: Overlap<S>,
+              H: BuildHasher
+    {
+        let start: &Node<S, K> = if self.revision == Some(tree.revision) {
+            // the node is alive, because the tree was not modified
+            unsafe { &*self.anchor }
+        } else {
+            &tree.root
+        };
+
+        let anchor = match volume.bounding_box() {
+            Some(bb) => find_anchor(start, &bb),
+            None => &*tree.root,
+        };
+
+        // items stored in the nodes above the anchor come first in the tree order
+        let mut ancestors = Vec::new();
+        let mut node = anchor.get_parent();
+        while let Some(parent) = node {
+            ancestors.push(parent);
+            node = parent.get_parent();
+        }
+
+        let mut found = HashSet::new();
+        let mut items = Vec::new();
+        for ancestor in ancestors.into_iter().rev() {
+            for leaf in &ancestor.leafs {
+                if volume.overlaps_aabb(&leaf.bb) && found.insert(leaf.id.clone()) {
+                    items.push(leaf.id.clone());
+                }
+            }
+        }
+
+        let unique = tree.ctx.mode == StraddleMode::Children;
+        for leaf in OverlapIter::new(&volume, anchor, unique) {
+            if found.insert(leaf.id.clone()) {
+                items.push(leaf.id.clone());
+            }
+        }
+
+        let changes = {
+            let previous: HashSet<&K> = self.items.iter().collect();
+            OverlapChanges {
+                entered: items.iter().filter(|id| !previous.contains(id)).cloned().collect(),
+                exited: self.items.iter().filter(|id| !found.contains(*id)).cloned().collect(),
+            }
+        };
+
+        self.revision = Some(tree.revision);
+        self.anchor = anchor;
+        self.items = items;
+
+        changes
+    }
+}
+
+impl<S, K> Default for OverlapTracker<S, K>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    fn default() -> OverlapTracker<S, K> {
+        OverlapTracker::new()
+    }
+}
+
+/// Find the smallest node around `start` whose volume strictly contains `bb`, or the root.
+///
+/// Items touching the boundary of a node can be stored outside of it, so a volume that
+/// touches the boundary is searched from a larger node.
+fn find_anchor<'a, S, K>(start: &'a Node<S, K>, bb: &Aabb3<S>) -> &'a Node<S, K>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    let mut node = start;
+    while !strictly_contains(&node.bb, bb) {
+        match node.get_parent() {
+            Some(parent) => node = parent,
+            None => return node,
+        }
+    }
+
+    while node.is_branch {
+        match (0..8).map(|i| node.get_node(i)).find(|child| strictly_contains(&child.bb, bb)) {
+            Some(child) => node = child,
+            None => break,
+        }
+    }
+
+    node
+}
+
+fn strictly_contains<S>(outer: &Aabb3<S>, inner: &Aabb3<S>) -> bool
+    where S: BaseNum
+{
+    outer.min.x < inner.min.x && inner.max.x < outer.max.x && outer.min.y < inner.min.y &&
+    inner.max.y < outer.max.y && outer.min.z < inner.min.z && inner.max.z < outer.max.z
+}
@@ -0,0 +1,346 @@
+use collections::HashSet;
+use std::cmp::{self, Ordering};
+use std::hash::Hash;
+use alloc::collections::BinaryHeap;
+use prelude::*;
+use cgmath::*;
+use collision::*;
+use Item;
+use node::Node;
+use super::{distance2, contains_point, ray_entry};
+
+/// Find the items visible from `viewpoint`, in front-to-back order.
+///
+/// Nodes and items are visited nearest first. Every visible item for which `is_occluder`
+/// returns `true` is drawn into a cube map depth buffer with `resolution` cells along the
+/// edge of each face, and later items and nodes are hidden if all cells they cover are
+/// occluded by something nearer. Occluders are drawn only into the cells they cover
+/// completely, and items are tested against all cells they may cover, so an item can be
+/// reported visible when it is not, but never the other way around.
+pub fn visible<S, K, F>(root: &Node<S, K>,
+                        viewpoint: Point3<S>,
+                        resolution: usize,
+                        unique: bool,
+                        mut is_occluder: F)
+                        -> Vec<&Item<S, K>>
+    where S: BaseFloat,
+          K: Clone + Eq + Hash,
+          F: FnMut(&Item<S, K>) -> bool
+{
+    let mut buffer = DepthBuffer::new(viewpoint, resolution);
+    let mut seen = HashSet::new();
+    let mut visible = Vec::new();
+
+    let mut queue = BinaryHeap::new();
+    if let Some(content) = root.content {
+        queue.push(Entry::node(root, distance2(&content, viewpoint)));
+    }
+
+    while let Some(entry) = queue.pop() {
+        match entry.target {
+            Target::Node(node) => {
+                if buffer.is_hidden(&node.content.unwrap(), entry.distance2) {
+                    continue;
+                }
+
+                for leaf in &node.leafs {
+                    queue.push(Entry::item(leaf, distance2(&leaf.bb, viewpoint)));
+                }
+
+                if node.is_branch {
+                    for i in 0..8 {
+                        let child = node.get_node(i);
+                        if let Some(content) = child.content {
+                            queue.push(Entry::node(child, distance2(&content, viewpoint)));
+                        }
+                    }
+                }
+            }
+            Target::Item(item) => {
+                if unique && seen.contains(&item.id) {
+                    continue;
+                }
+
+                if buffer.is_hidden(&item.bb, entry.distance2) {
+                    continue;
+                }
+
+                if unique {
+                    seen.insert(item.id.clone());
+                }
+                if is_occluder(item) {
+                    buffer.draw(&item.bb);
+                }
+                visible.push(item);
+            }
+        }
+    }
+
+    visible
+}
+
+enum Target<'a, S: 'a, K: 'a>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    Node(&'a Node<S, K>),
+    Item(&'a Item<S, K>),
+}
+
+/// Queue entry, ordered so that the nearest entry is popped first.
+struct Entry<'a, S: 'a, K: 'a>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    distance2: S,
+    target: Target<'a, S, K>,
+}
+
+impl<'a, S, K> Entry<'a, S, K>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    fn node(node: &'a Node<S, K>, distance2: S) -> Entry<'a, S, K> {
+        Entry {
+            distance2: distance2,
+            target: Target::Node(node),
+        }
+    }
+
+    fn item(item: &'a Item<S, K>, distance2: S) -> Entry<'a, S, K> {
+        Entry {
+            distance2: distance2,
+            target: Target::Item(item),
+        }
+    }
+}
+
+impl<'a, S, K> PartialEq for Entry<'a, S, K>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    fn eq(&self, other: &Entry<'a, S, K>) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<'a, S, K> Eq for Entry<'a, S, K>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{}
+
+impl<'a, S, K> cmp::PartialOrd for Entry<'a, S, K>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    fn partial_cmp(&self, other: &Entry<'a, S, K>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a, S, K> Ord for Entry<'a, S, K>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    fn cmp(&self, other: &Entry<'a, S, K>) -> Ordering {
+        // reversed for the max-heap, nodes before items at the same distance so that
+        // items stored deeper are queued before
+        other.distance2
+            .partial_cmp(&self.distance2)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| match (&self.target, &other.target) {
+                (&Target::Node(_), &Target::Item(_)) => Ordering::Greater,
+                (&Target::Item(_), &Target::Node(_)) => Ordering::Less,
+                _ => Ordering::Equal,
+            })
+    }
+}
+
+/// Cube map around the viewpoint, storing in every cell the squared distance beyond which
+/// everything seen through the cell is occluded.
+///
+/// Face `f` looks along the axis `f / 2`, in the positive direction for even `f`. A cell
+/// covers the directions `(u, v)` of its square on the face at distance `1`, where `u` and
+/// `v` are the components of the next two axes.
+struct DepthBuffer<S> {
+    viewpoint: Point3<S>,
+    resolution: usize,
+    depth2: Vec<S>,
+}
+
+/// Range of cells on a face, both ends inclusive.
+struct CellRect {
+    u: (usize, usize),
+    v: (usize, usize),
+}
+
+impl<S> DepthBuffer<S>
+    where S: BaseFloat
+{
+    fn new(viewpoint: Point3<S>, resolution: usize) -> DepthBuffer<S> {
+        DepthBuffer {
+            viewpoint: viewpoint,
+            resolution: resolution,
+            depth2: vec![S::infinity(); 6 * resolution * resolution],
+        }
+    }
+
+    /// Returns `true` if all cells that `bb` may cover are occluded before `distance2`.
+    fn is_hidden(&self, bb: &Aabb3<S>, distance2: S) -> bool {
+        if contains_point(bb, self.viewpoint) || self.resolution == 0 {
+            return false;
+        }
+
+        for face in 0..6 {
+            if let Some(rect) = self.cover(face, bb) {
+                for u in rect.u.0..rect.u.1 + 1 {
+                    for v in rect.v.0..rect.v.1 + 1 {
+                        if self.depth2[self.index(face, u, v)] >= distance2 {
+                            return false;
+                        }
+                    }
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Occlude the cells that are completely covered by `bb`, behind the farthest point
+    /// where a ray through the cell enters it.
+    fn draw(&mut self, bb: &Aabb3<S>) {
+        if contains_point(bb, self.viewpoint) || self.resolution == 0 {
+            return;
+        }
+
+        for face in 0..6 {
+            let rect = match self.cover(face, bb) {
+                Some(rect) => rect,
+                None => continue,
+            };
+
+            // squared distance to the entry point of the ray through every grid corner
+            let width = rect.u.1 - rect.u.0 + 2;
+            let mut entries = Vec::with_capacity(width * (rect.v.1 - rect.v.0 + 2));
+            for v in rect.v.0..rect.v.1 + 2 {
+                for u in rect.u.0..rect.u.1 + 2 {
+                    let dir = self.direction(face, u, v);
+                    let ray = Ray3::new(self.viewpoint, dir);
+                    entries.push(ray_entry(&ray, bb).map(|t| t * t * dir.magnitude2()));
+                }
+            }
+
+            // the directions of rays entering the box within a distance form a convex cone,
+            // so a cell is covered if all of its corners are, and no ray through it enters
+            // further than through the corners
+            for v in 0..rect.v.1 - rect.v.0 + 1 {
+                for u in 0..rect.u.1 - rect.u.0 + 1 {
+                    let corners = [entries[v * width + u],
+                                   entries[v * width + u + 1],
+                                   entries[(v + 1) * width + u],
+                                   entries[(v + 1) * width + u + 1]];
+                    let mut depth2 = S::zero();
+                    for corner in &corners {
+                        depth2 = match *corner {
+                            Some(entry2) => depth2.max(entry2),
+                            None => S::infinity(),
+                        };
+                    }
+
+                    let index = self.index(face, rect.u.0 + u, rect.v.0 + v);
+                    self.depth2[index] = self.depth2[index].min(depth2);
+                }
+            }
+        }
+    }
+
+    /// Cells of `face` that the projection of `bb` may cover, `None` if it is not visible
+    /// through the face.
+    fn cover(&self, face: usize, bb: &Aabb3<S>) -> Option<CellRect> {
+        let (axis, sign) = face_axis::<S>(face);
+        let (b, c) = ((axis + 1) % 3, (axis + 2) % 3);
+        let (q_min, q_max) = (bb.min - self.viewpoint, bb.max - self.viewpoint);
+
+        let (d_min, d_max) = if sign > S::zero() {
+            (q_min[axis], q_max[axis])
+        } else {
+            (-q_max[axis], -q_min[axis])
+        };
+
+        // points seen through the face are at least as far along its axis as along the
+        // other two, so the nearer part of the box can be cut off
+        let d_min = d_min.max(gap(q_min[b], q_max[b])).max(gap(q_min[c], q_max[c]));
+        if d_min <= S::zero() || d_max < d_min {
+            return None;
+        }
+
+        let project = |lo: S, hi: S| {
+            let ends = [lo / d_min, lo / d_max, hi / d_min, hi / d_max];
+            (ends.iter().fold(S::infinity(), |min, &x| min.min(x)),
+             ends.iter().fold(S::neg_infinity(), |max, &x| max.max(x)))
+        };
+        let (u_min, u_max) = project(q_min[b], q_max[b]);
+        let (v_min, v_max) = project(q_min[c], q_max[c]);
+
+        let one = S::one();
+        if u_max < -one || u_min > one || v_max < -one || v_min > one {
+            return None;
+        }
+
+        Some(CellRect {
+            u: (self.cell(u_min), self.cell(u_max)),
+            v: (self.cell(v_min), self.cell(v_max)),
+        })
+    }
+
+    /// Cell containing the face coordinate `x`, clamped to the face.
+    fn cell(&self, x: S) -> usize {
+        let res = S::from(self.resolution).unwrap();
+        let two = S::one() + S::one();
+        let cell = ((x + S::one()) / two * res).floor();
+        if cell <= S::zero() {
+            0
+        } else if cell >= res {
+            self.resolution - 1
+        } else {
+            cell.to_usize().unwrap()
+        }
+    }
+
+    /// Direction through the corner `(u, v)` of the cell grid of `face`.
+    fn direction(&self, face: usize, u: usize, v: usize) -> Vector3<S> {
+        let (axis, sign) = face_axis::<S>(face);
+        let res = S::from(self.resolution).unwrap();
+        let two = S::one() + S::one();
+        let to_face = |i: usize| S::from(i).unwrap() / res * two - S::one();
+
+        let mut dir = Vector3::zero();
+        dir[axis] = sign;
+        dir[(axis + 1) % 3] = to_face(u);
+        dir[(axis + 2) % 3] = to_face(v);
+        dir
+    }
+
+    fn index(&self, face: usize, u: usize, v: usize) -> usize {
+        (face * self.resolution + v) * self.resolution + u
+    }
+}
+
+fn face_axis<S>(face: usize) -> (usize, S)
+    where S: BaseFloat
+{
+    (face / 2, if face % 2 == 0 { S::one() } else { -S::one() })
+}
+
+/// Distance from zero to the range `lo..hi`.
+fn gap<S>(lo: S, hi: S) -> S
+    where S: BaseFloat
+{
+    if lo > S::zero() {
+        lo
+    } else if hi < S::zero() {
+        -hi
+    } else {
+        S::zero()
+    }
+}
@@ -0,0 +1,110 @@
+use std::hash::{Hash, BuildHasher};
+use prelude::*;
+use cgmath::*;
+use node::{Node, Context};
+
+/// Position of the incremental rebalancing in the tree, see `Octree::rebalance_step`.
+///
+/// Nodes are visited in post-order, so that children are balanced before their parent
+/// decides whether to collapse them. The position is kept as a path of octant indices, which
+/// stays valid while the tree is modified between steps.
+pub struct Cursor {
+    path: Vec<usize>,
+    /// Descend to the first leaf below the position before visiting it.
+    descend: bool,
+    changed: bool,
+}
+
+impl Cursor {
+    pub fn new() -> Cursor {
+        Cursor {
+            path: Vec::new(),
+            descend: true,
+            changed: false,
+        }
+    }
+}
+
+/// Result of a rebalancing `step`.
+pub struct Progress {
+    /// The structure of some node was changed.
+    pub changed: bool,
+    /// A pass over the whole tree finished without any changes.
+    pub balanced: bool,
+}
+
+/// Visit at most `budget` nodes.
+pub fn step<S, K, H>(root: &mut Node<S, K>,
+                     ctx: &mut Context<S, K, H>,
+                     cursor: &mut Cursor,
+                     budget: usize)
+                     -> Progress
+    where S: BaseNum,
+          K: Clone + Eq + Hash,
+          H: BuildHasher
+{
+    let mut changed = false;
+    for _ in 0..budget {
+        let node = resolve(root, cursor);
+        if node.rebalance(ctx) {
+            changed = true;
+            cursor.changed = true;
+        }
+        if !ctx.tight_bounds {
+            // children were visited first, so their bounds are already tight
+            let content = node.compute_content();
+            node.set_content(content);
+        }
+
+        match cursor.path.pop() {
+            Some(octant) if octant < 7 => {
+                cursor.path.push(octant + 1);
+                cursor.descend = true;
+            }
+            Some(_) => cursor.descend = false,
+            None => {
+                // the root was visited last, start the next pass
+                let balanced = !cursor.changed;
+                cursor.changed = false;
+                cursor.descend = true;
+                if balanced {
+                    return Progress {
+                        changed: changed,
+                        balanced: true,
+                    };
+                }
+            }
+        }
+    }
+
+    Progress {
+        changed: changed,
+        balanced: false,
+    }
+}
+
+/// Find the node at cursor position, shortening the path if part of it was collapsed.
+fn resolve<'a, S, K>(root: &'a mut Node<S, K>, cursor: &mut Cursor) -> &'a mut Node<S, K>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    let mut node = root;
+
+    for depth in 0..cursor.path.len() {
+        if !node.is_branch {
+            cursor.path.truncate(depth);
+            cursor.descend = false;
+            return node;
+        }
+        node = { node }.get_node_mut(cursor.path[depth]);
+    }
+
+    if cursor.descend {
+        while node.is_branch {
+            cursor.path.push(0);
+            node = { node }.get_node_mut(0);
+        }
+    }
+
+    node
+}
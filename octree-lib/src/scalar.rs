@@ -0,0 +1,57 @@
+use cgmath::BaseNum;
+
+/// Coordinate type that can be stored in the tree.
+///
+/// Implemented for `f32`, `f64` and the primitive integer types. Nodes are split at
+/// `min + (max - min) / 2`, which for integers rounds towards `min`: an odd-sized cell
+/// `[0, 5]` is split into `[0, 2]` and `[2, 5]`. A node is only split if the midpoint lies
+/// strictly inside it on every axis, so cells narrower than `2` on any axis are never
+/// subdivided when using integers.
+pub trait Scalar: BaseNum {
+    /// Returns `false` for NaN and infinite values.
+    fn is_finite_scalar(self) -> bool;
+
+    /// Subtract `other`, returning `None` if the result does not fit into the type.
+    ///
+    /// Floating point subtraction always succeeds, rounding or going to infinity instead.
+    fn checked_sub_scalar(self, other: Self) -> Option<Self>;
+}
+
+macro_rules! impl_scalar_int {
+    ($($t:ty),*) => {
+        $(
+            impl Scalar for $t {
+                #[inline]
+                fn is_finite_scalar(self) -> bool {
+                    true
+                }
+
+                #[inline]
+                fn checked_sub_scalar(self, other: $t) -> Option<$t> {
+                    self.checked_sub(other)
+                }
+            }
+        )*
+    }
+}
+
+macro_rules! impl_scalar_float {
+    ($($t:ty),*) => {
+        $(
+            impl Scalar for $t {
+                #[inline]
+                fn is_finite_scalar(self) -> bool {
+                    self.is_finite()
+                }
+
+                #[inline]
+                fn checked_sub_scalar(self, other: $t) -> Option<$t> {
+                    Some(self - other)
+                }
+            }
+        )*
+    }
+}
+
+impl_scalar_int!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+impl_scalar_float!(f32, f64);
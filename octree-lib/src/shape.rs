@@ -0,0 +1,309 @@
+use cgmath::*;
+use collision::*;
+use query;
+
+/// Volume that can be tested for overlap with axis aligned boxes, see `Octree::overlapping`.
+///
+/// Implemented for `Aabb3`, `collision::Sphere`, `Capsule` and `OrientedBox`. Custom convex
+/// volumes can implement it too.
+pub trait Overlap<S>
+    where S: BaseNum
+{
+    /// Exact test, used for item volumes.
+    fn overlaps_aabb(&self, bb: &Aabb3<S>) -> bool;
+
+    /// Conservative test, used to skip nodes. It may return `true` for boxes that do not
+    /// overlap, but never `false` for boxes that do.
+    ///
+    /// Defaults to the exact test, override it if there is a cheaper approximation.
+    fn may_overlap_aabb(&self, bb: &Aabb3<S>) -> bool {
+        self.overlaps_aabb(bb)
+    }
+
+    /// Axis aligned box enclosing the volume, `None` if it is unbounded or unknown.
+    ///
+    /// Lets `OverlapTracker` start the search below the root node.
+    fn bounding_box(&self) -> Option<Aabb3<S>> {
+        None
+    }
+}
+
+impl<S> Overlap<S> for Aabb3<S>
+    where S: BaseNum
+{
+    fn overlaps_aabb(&self, bb: &Aabb3<S>) -> bool {
+        self.min.x <= bb.max.x && bb.min.x <= self.max.x && self.min.y <= bb.max.y &&
+        bb.min.y <= self.max.y && self.min.z <= bb.max.z && bb.min.z <= self.max.z
+    }
+
+    fn bounding_box(&self) -> Option<Aabb3<S>> {
+        Some(*self)
+    }
+}
+
+impl<S> Overlap<S> for Sphere<S>
+    where S: BaseFloat
+{
+    fn overlaps_aabb(&self, bb: &Aabb3<S>) -> bool {
+        query::distance2(bb, self.center) <= self.radius * self.radius
+    }
+
+    fn bounding_box(&self) -> Option<Aabb3<S>> {
+        let r = Vector3::new(self.radius, self.radius, self.radius);
+        Some(Aabb3::new(self.center + -r, self.center + r))
+    }
+}
+
+impl<S, V> Overlap<S> for &V
+    where S: BaseNum,
+          V: Overlap<S> + ?Sized
+{
+    fn overlaps_aabb(&self, bb: &Aabb3<S>) -> bool {
+        (**self).overlaps_aabb(bb)
+    }
+
+    fn may_overlap_aabb(&self, bb: &Aabb3<S>) -> bool {
+        (**self).may_overlap_aabb(bb)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb3<S>> {
+        (**self).bounding_box()
+    }
+}
+
+/// Volume swept by a sphere of `radius` moving from `start` to `end`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Capsule<S>
+    where S: BaseFloat
+{
+    pub start: Point3<S>,
+    pub end: Point3<S>,
+    pub radius: S,
+}
+
+impl<S> Capsule<S>
+    where S: BaseFloat
+{
+    pub fn new(start: Point3<S>, end: Point3<S>, radius: S) -> Capsule<S> {
+        Capsule {
+            start: start,
+            end: end,
+            radius: radius,
+        }
+    }
+
+    /// Axis aligned box enclosing the capsule.
+    pub fn bounds(&self) -> Aabb3<S> {
+        let r = Vector3::new(self.radius, self.radius, self.radius);
+        let bb = Aabb3::new(self.start, self.end);
+        Aabb3::new(bb.min + -r, bb.max + r)
+    }
+}
+
+impl<S> Overlap<S> for Capsule<S>
+    where S: BaseFloat
+{
+    fn overlaps_aabb(&self, bb: &Aabb3<S>) -> bool {
+        if segment_hits_aabb(self.start, self.end, bb) {
+            return true;
+        }
+
+        // segment is outside the box, so the closest point pair has one point either at
+        // a segment end or on a box edge
+        let r2 = self.radius * self.radius;
+        if query::distance2(bb, self.start) <= r2 || query::distance2(bb, self.end) <= r2 {
+            return true;
+        }
+
+        let corners = bb.to_corners();
+        BOX_EDGES.iter().any(|&(a, b)| {
+            segment_distance2(self.start, self.end, corners[a], corners[b]) <= r2
+        })
+    }
+
+    fn may_overlap_aabb(&self, bb: &Aabb3<S>) -> bool {
+        self.bounds().overlaps_aabb(bb)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb3<S>> {
+        Some(self.bounds())
+    }
+}
+
+/// Box with arbitrary orientation.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct OrientedBox<S>
+    where S: BaseFloat
+{
+    pub center: Point3<S>,
+    /// Unit length and mutually perpendicular box axes.
+    pub axes: [Vector3<S>; 3],
+    /// Half of the box size along each of the `axes`.
+    pub half_extents: Vector3<S>,
+}
+
+impl<S> OrientedBox<S>
+    where S: BaseFloat
+{
+    /// Create a box of `half_extents` size, rotated around `center` by `rotation`.
+    pub fn new(center: Point3<S>,
+               half_extents: Vector3<S>,
+               rotation: Quaternion<S>)
+               -> OrientedBox<S> {
+        OrientedBox {
+            center: center,
+            axes: [rotation.rotate_vector(Vector3::unit_x()),
+                   rotation.rotate_vector(Vector3::unit_y()),
+                   rotation.rotate_vector(Vector3::unit_z())],
+            half_extents: half_extents,
+        }
+    }
+
+    /// Axis aligned box enclosing the oriented box.
+    pub fn bounds(&self) -> Aabb3<S> {
+        let mut r = Vector3::zero();
+        for i in 0..3 {
+            for j in 0..3 {
+                r[i] += self.axes[j][i].abs() * self.half_extents[j];
+            }
+        }
+        Aabb3::new(self.center + -r, self.center + r)
+    }
+}
+
+impl<S> Overlap<S> for OrientedBox<S>
+    where S: BaseFloat
+{
+    /// Separating axis test with the 3 world axes, 3 box axes and their 9 cross products.
+    fn overlaps_aabb(&self, bb: &Aabb3<S>) -> bool {
+        let two = S::one() + S::one();
+        let ea = (bb.max - bb.min) / two;
+        let eb = self.half_extents;
+        let t = self.center - (bb.min + ea);
+
+        // r[i][j] is the world axis i component of box axis j, the epsilon keeps cross
+        // products of nearly parallel axes from rejecting overlapping boxes
+        let mut r = [[S::zero(); 3]; 3];
+        let mut abs_r = [[S::zero(); 3]; 3];
+        for i in 0..3 {
+            for j in 0..3 {
+                r[i][j] = self.axes[j][i];
+                abs_r[i][j] = r[i][j].abs() + S::approx_epsilon();
+            }
+        }
+
+        for i in 0..3 {
+            let rb = eb[0] * abs_r[i][0] + eb[1] * abs_r[i][1] + eb[2] * abs_r[i][2];
+            if t[i].abs() > ea[i] + rb {
+                return false;
+            }
+        }
+
+        for j in 0..3 {
+            let ra = ea[0] * abs_r[0][j] + ea[1] * abs_r[1][j] + ea[2] * abs_r[2][j];
+            let d = t[0] * r[0][j] + t[1] * r[1][j] + t[2] * r[2][j];
+            if d.abs() > ra + eb[j] {
+                return false;
+            }
+        }
+
+        for i in 0..3 {
+            let (i1, i2) = ((i + 1) % 3, (i + 2) % 3);
+            for j in 0..3 {
+                let (j1, j2) = ((j + 1) % 3, (j + 2) % 3);
+                let ra = ea[i1] * abs_r[i2][j] + ea[i2] * abs_r[i1][j];
+                let rb = eb[j1] * abs_r[i][j2] + eb[j2] * abs_r[i][j1];
+                let d = t[i2] * r[i1][j] - t[i1] * r[i2][j];
+                if d.abs() > ra + rb {
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    fn may_overlap_aabb(&self, bb: &Aabb3<S>) -> bool {
+        self.bounds().overlaps_aabb(bb)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb3<S>> {
+        Some(self.bounds())
+    }
+}
+
+/// Corner index pairs of the 12 box edges, in `Aabb3::to_corners` order.
+const BOX_EDGES: [(usize, usize); 12] = [(0, 1), (2, 3), (4, 5), (6, 7), (0, 2), (1, 3),
+                                         (4, 6), (5, 7), (0, 4), (1, 5), (2, 6), (3, 7)];
+
+/// Check if segment from `a` to `b` passes through `bb`, by clipping it with the box slabs.
+fn segment_hits_aabb<S>(a: Point3<S>, b: Point3<S>, bb: &Aabb3<S>) -> bool
+    where S: BaseFloat
+{
+    let d = b - a;
+    let mut t_min = S::zero();
+    let mut t_max = S::one();
+
+    for i in 0..3 {
+        if d[i] == S::zero() {
+            if a[i] < bb.min[i] || a[i] > bb.max[i] {
+                return false;
+            }
+            continue;
+        }
+
+        let t1 = (bb.min[i] - a[i]) / d[i];
+        let t2 = (bb.max[i] - a[i]) / d[i];
+        t_min = t_min.max(t1.min(t2));
+        t_max = t_max.min(t1.max(t2));
+        if t_min > t_max {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Squared distance between closest points of segments `p1`-`q1` and `p2`-`q2`.
+fn segment_distance2<S>(p1: Point3<S>, q1: Point3<S>, p2: Point3<S>, q2: Point3<S>) -> S
+    where S: BaseFloat
+{
+    let clamp = |v: S| v.max(S::zero()).min(S::one());
+
+    let d1 = q1 - p1;
+    let d2 = q2 - p2;
+    let r = p1 - p2;
+    let a = d1.dot(d1);
+    let e = d2.dot(d2);
+    let f = d2.dot(r);
+
+    let (s, t) = if a == S::zero() && e == S::zero() {
+        (S::zero(), S::zero())
+    } else if a == S::zero() {
+        (S::zero(), clamp(f / e))
+    } else {
+        let c = d1.dot(r);
+        if e == S::zero() {
+            (clamp(-c / a), S::zero())
+        } else {
+            let b = d1.dot(d2);
+            let denom = a * e - b * b;
+            let s = if denom != S::zero() {
+                clamp((b * f - c * e) / denom)
+            } else {
+                S::zero()
+            };
+            let t = (b * s + f) / e;
+            if t < S::zero() {
+                (clamp(-c / a), S::zero())
+            } else if t > S::one() {
+                (clamp((b - c) / a), S::one())
+            } else {
+                (s, t)
+            }
+        }
+    };
+
+    let closest = (p1 + d1 * s) - (p2 + d2 * t);
+    closest.dot(closest)
+}
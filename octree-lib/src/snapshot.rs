@@ -0,0 +1,233 @@
+use collections::HashSet;
+use std::hash::Hash;
+use alloc::sync::Arc;
+use prelude::*;
+use cgmath::*;
+use collision::*;
+use Item;
+use shape::Overlap;
+use mode::StraddleMode;
+use node::Node;
+
+/// Immutable copy of the tree taken by `Octree::snapshot`.
+///
+/// Snapshots can be sent to other threads and read while the tree is modified. Cloning
+/// is cheap, the nodes are shared.
+pub struct Snapshot<S, K>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    root: Arc<SnapshotNode<S, K>>,
+    mode: StraddleMode,
+    revision: usize,
+}
+
+/// Copy of a tree node. Unchanged subtrees are shared between consecutive snapshots.
+pub struct SnapshotNode<S, K>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    content: Option<Aabb3<S>>,
+    items: Vec<Item<S, K>>,
+    /// Empty for leaf nodes, otherwise the 8 children in octant order.
+    children: Vec<Arc<SnapshotNode<S, K>>>,
+}
+
+impl<S, K> Clone for Snapshot<S, K>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    fn clone(&self) -> Snapshot<S, K> {
+        Snapshot {
+            root: self.root.clone(),
+            mode: self.mode,
+            revision: self.revision,
+        }
+    }
+}
+
+impl<S, K> Snapshot<S, K>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    pub fn new(root: Arc<SnapshotNode<S, K>>,
+               mode: StraddleMode,
+               revision: usize)
+               -> Snapshot<S, K> {
+        Snapshot {
+            root: root,
+            mode: mode,
+            revision: revision,
+        }
+    }
+
+    /// Revision of the tree when the snapshot was taken, see `Octree::revision`.
+    pub fn revision(&self) -> usize {
+        self.revision
+    }
+
+    /// Get iterator over all items, in the tree iteration order.
+    pub fn iter<'a>(&'a self) -> SnapshotIter<'a, S, K, Aabb3<S>> {
+        SnapshotIter::new(None, &self.root, self.mode == StraddleMode::Children)
+    }
+
+    /// Get iterator over all items whose volume overlaps `volume`, see
+    /// `Octree::overlapping`.
+    pub fn overlapping<'a, V>(&'a self, volume: V) -> SnapshotIter<'a, S, K, V>
+        where V: Overlap<S>
+    {
+        SnapshotIter::new(Some(volume), &self.root, self.mode == StraddleMode::Children)
+    }
+}
+
+impl<'a, S, K> IntoIterator for &'a Snapshot<S, K>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    type Item = &'a Item<S, K>;
+    type IntoIter = SnapshotIter<'a, S, K, Aabb3<S>>;
+
+    fn into_iter(self) -> SnapshotIter<'a, S, K, Aabb3<S>> {
+        self.iter()
+    }
+}
+
+/// Copy the nodes changed since the `previous` snapshot of `node`, sharing the rest.
+pub fn build<S, K>(node: &mut Node<S, K>,
+                   previous: Option<&Arc<SnapshotNode<S, K>>>)
+                   -> Arc<SnapshotNode<S, K>>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    if !node.dirty {
+        if let Some(previous) = previous {
+            return previous.clone();
+        }
+    }
+    node.dirty = false;
+
+    let mut children = Vec::new();
+    if node.is_branch {
+        for i in 0..8 {
+            let previous_child = previous.and_then(|previous| previous.children.get(i));
+            children.push(build(node.get_node_mut(i), previous_child));
+        }
+    }
+
+    Arc::new(SnapshotNode {
+        content: node.content,
+        items: node.leafs.clone(),
+        children: children,
+    })
+}
+
+struct State<'a, S: 'a, K: 'a>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    node: &'a SnapshotNode<S, K>,
+    leaf_index: usize,
+    node_index: usize,
+}
+
+/// Iterator over snapshot items, optionally only those overlapping a volume.
+pub struct SnapshotIter<'a, S: 'a, K: 'a, V>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    volume: Option<V>,
+    stack: Vec<State<'a, S, K>>,
+    seen: Option<HashSet<K>>,
+}
+
+impl<'a, S: 'a, K: 'a, V> SnapshotIter<'a, S, K, V>
+    where S: BaseNum,
+          K: Clone + Eq + Hash,
+          V: Overlap<S>
+{
+    fn new<'r>(volume: Option<V>,
+               node: &'r SnapshotNode<S, K>,
+               unique: bool)
+               -> SnapshotIter<'r, S, K, V> {
+        SnapshotIter {
+            volume: volume,
+            stack: vec![State {
+                node: node,
+                leaf_index: 0,
+                node_index: 0,
+            }],
+            seen: if unique { Some(HashSet::new()) } else { None },
+        }
+    }
+
+    fn may_overlap(&self, content: &Option<Aabb3<S>>) -> bool {
+        match (content, &self.volume) {
+            (Some(content), Some(volume)) => volume.may_overlap_aabb(content),
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+}
+
+impl<'a, S: 'a, K: 'a, V> Iterator for SnapshotIter<'a, S, K, V>
+    where S: BaseNum,
+          K: Clone + Eq + Hash,
+          V: Overlap<S>
+{
+    type Item = &'a Item<S, K>;
+
+    fn next(&mut self) -> Option<&'a Item<S, K>> {
+        loop {
+            let child = {
+                let state = self.stack.last_mut()?;
+
+                let node: &'a SnapshotNode<S, K> = state.node;
+                if state.leaf_index < node.items.len() {
+                    let item = &node.items[state.leaf_index];
+                    state.leaf_index += 1;
+
+                    if let Some(ref seen) = self.seen {
+                        if seen.contains(&item.id) {
+                            continue;
+                        }
+                    }
+
+                    let overlaps = match self.volume {
+                        Some(ref volume) => volume.overlaps_aabb(&item.bb),
+                        None => true,
+                    };
+                    if overlaps {
+                        if let Some(ref mut seen) = self.seen {
+                            seen.insert(item.id.clone());
+                        }
+                        return Some(item);
+                    }
+
+                    continue;
+                }
+
+                if state.node_index < node.children.len() {
+                    state.node_index += 1;
+                    Some(&*node.children[state.node_index - 1])
+                } else {
+                    None
+                }
+            };
+
+            match child {
+                Some(child) => {
+                    if self.may_overlap(&child.content) {
+                        self.stack.push(State {
+                            node: child,
+                            leaf_index: 0,
+                            node_index: 0,
+                        });
+                    }
+                }
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use std::hash::Hash;
+use cgmath::*;
+use Item;
+use node_ref::NodeRef;
+
+/// Visitor decision on how to continue the traversal.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Visit {
+    /// Continue into node items and children.
+    Continue,
+    /// Visit the rest of this node's items, but not its children.
+    SkipChildren,
+    /// Do not visit the rest of this node's items and children.
+    SkipNode,
+    /// Stop the traversal.
+    Stop,
+}
+
+/// Custom tree traversal, see `Octree::visit`.
+///
+/// Nodes are visited depth-first. After a node is entered, its items are visited, followed
+/// by its children in octant order.
+pub trait Visitor<S, K>
+    where S: BaseNum,
+          K: Clone + Eq + Hash
+{
+    /// Called when the traversal reaches a node.
+    fn enter_node(&mut self, node: NodeRef<S, K>) -> Visit;
+
+    /// Called for every item of a node that was not entered with `Visit::SkipNode`.
+    fn visit_item(&mut self, item: &Item<S, K>) -> Visit;
+}
+
+/// Walk the subtree of `node`, returns `false` if the visitor requested a stop.
+pub fn walk<S, K, V>(node: NodeRef<S, K>, visitor: &mut V) -> bool
+    where S: BaseNum,
+          K: Clone + Eq + Hash,
+          V: Visitor<S, K> + ?Sized
+{
+    let mut descend = match visitor.enter_node(node) {
+        Visit::Continue => true,
+        Visit::SkipChildren => false,
+        Visit::SkipNode => return true,
+        Visit::Stop => return false,
+    };
+
+    for item in node.items() {
+        match visitor.visit_item(item) {
+            Visit::Continue => (),
+            Visit::SkipChildren => descend = false,
+            Visit::SkipNode => return true,
+            Visit::Stop => return false,
+        }
+    }
+
+    if !descend {
+        return true;
+    }
+
+    for child in node.children() {
+        if !walk(child, visitor) {
+            return false;
+        }
+    }
+
+    true
+}